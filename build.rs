@@ -0,0 +1,38 @@
+use std::env;
+use std::path;
+use std::process;
+
+fn git(args: &[&str]) -> Option<String> {
+    process::Command::new("git")
+        .args(args)
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|output| output.trim().to_owned())
+}
+
+fn main() {
+    let commit = git(&["rev-parse", "--short", "HEAD"]).unwrap_or_default();
+
+    println!("cargo:rustc-env=RIPPIFY_GIT_COMMIT={}", commit);
+    println!(
+        "cargo:rustc-env=RIPPIFY_TARGET={}",
+        env::var("TARGET").unwrap_or_default()
+    );
+
+    // HEAD only changes when switching branches, a commit moves the branch it points to. Branches can also be packed
+    // into packed-refs. Files that don't exist aren't watched, cargo would run this on every build otherwise
+    if let Some(git_dir) = git(&["rev-parse", "--git-dir"]) {
+        let git_dir = path::Path::new(&git_dir);
+        let mut watched = vec![git_dir.join("HEAD"), git_dir.join("packed-refs")];
+
+        if let Some(head_ref) = git(&["symbolic-ref", "-q", "HEAD"]) {
+            watched.push(git_dir.join(head_ref));
+        }
+
+        for file in watched.iter().filter(|file| file.exists()) {
+            println!("cargo:rerun-if-changed={}", file.display());
+        }
+    }
+}
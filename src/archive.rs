@@ -0,0 +1,84 @@
+use std::collections as coll;
+use std::fs;
+use std::io;
+use std::io::{Read, Seek, Write};
+use std::sync;
+
+// Ids of every track written so far, one per line. Unlike existing files it doesn't depend on the output format, so
+// files can be moved or renamed, and one archive shared by runs with different formats
+pub struct DownloadArchive {
+    state: sync::Mutex<ArchiveState>,
+}
+
+// Other processes can be appending to the same archive, so `read_len` keeps how much of it was read already
+struct ArchiveState {
+    ids: coll::HashSet<String>,
+    file: fs::File,
+    read_len: u64,
+}
+
+impl DownloadArchive {
+    pub fn open(path: &str) -> io::Result<DownloadArchive> {
+        let file = fs::OpenOptions::new().read(true).create(true).append(true).open(path)?;
+
+        let mut state = ArchiveState {
+            ids: coll::HashSet::new(),
+            file,
+            read_len: 0,
+        };
+        state.read_new_lines()?;
+
+        // A last line without a newline, as left by an editor, would otherwise be joined with the first id added
+        if state.file.metadata()?.len() > state.read_len {
+            state.file.write_all(b"\n")?;
+            state.read_new_lines()?;
+        }
+
+        Ok(DownloadArchive {
+            state: sync::Mutex::new(state),
+        })
+    }
+
+    // Ids that aren't known yet are looked up again in whatever was appended since the last read
+    pub fn contains(&self, id: &str) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.ids.contains(id) || (state.read_new_lines().is_ok() && state.ids.contains(id))
+    }
+
+    // Each id goes out in a single write to a file opened for appending, so lines written by other processes at the
+    // same time can't end up interleaved
+    pub fn add(&self, id: String) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+
+        if !state.ids.insert(id.clone()) {
+            return Ok(());
+        }
+
+        state.file.write_all(format!("{}\n", id).as_bytes())
+    }
+}
+
+impl ArchiveState {
+    fn read_new_lines(&mut self) -> io::Result<()> {
+        let mut appended = Vec::<u8>::new();
+        self.file.seek(io::SeekFrom::Start(self.read_len))?;
+        self.file.read_to_end(&mut appended)?;
+
+        // A line still being written is left for the next read
+        let complete = match appended.iter().rposition(|byte| *byte == b'\n') {
+            Some(pos) => &appended[..=pos],
+            None => return Ok(()),
+        };
+
+        for line in String::from_utf8_lossy(complete).lines() {
+            let line = line.trim();
+
+            if !line.is_empty() {
+                self.ids.insert(line.to_owned());
+            }
+        }
+
+        self.read_len += complete.len() as u64;
+        Ok(())
+    }
+}
@@ -0,0 +1,105 @@
+use base64::Engine;
+use colored::Colorize;
+use rand::distributions::{Alphanumeric, DistString};
+use sha2::Digest;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+// Spotify only accepts this redirect for the client id librespot logs in with
+const REDIRECT_PORT: u16 = 8898;
+const REDIRECT_URI: &str = "http://127.0.0.1:8898/login";
+const SCOPES: &str = "streaming user-read-private playlist-read-private playlist-read-collaborative user-library-read";
+
+#[derive(serde::Deserialize)]
+struct TokenResponse {
+    access_token: String,
+}
+
+// Authorization code flow with PKCE: the user logs in on the browser, which is sent back to a local listener with a
+// code we can trade for an access token. No client secret is involved
+pub async fn oauth_login(client_id: &str) -> Result<String, Box<dyn std::error::Error>> {
+    let verifier = Alphanumeric.sample_string(&mut rand::thread_rng(), 64);
+    let challenge = base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(sha2::Sha256::digest(verifier.as_bytes()));
+    let state = Alphanumeric.sample_string(&mut rand::thread_rng(), 16);
+
+    let listener = tokio::net::TcpListener::bind(("127.0.0.1", REDIRECT_PORT)).await?;
+
+    println!(
+        "{} Open this link in a browser to log in:\n\nhttps://accounts.spotify.com/authorize?response_type=code&client_id={}&redirect_uri={}&scope={}&code_challenge_method=S256&code_challenge={}&state={}\n",
+        "=>".green().bold(),
+        client_id,
+        urlencoding::encode(REDIRECT_URI),
+        urlencoding::encode(SCOPES),
+        challenge,
+        state
+    );
+
+    let code = loop {
+        let (mut stream, _) = listener.accept().await?;
+
+        let mut request_line = String::new();
+        tokio::io::BufReader::new(&mut stream)
+            .read_line(&mut request_line)
+            .await?;
+
+        let query = match redirect_query(&request_line) {
+            Some(query) => query,
+            None => continue,
+        };
+
+        let (body, result) = match (query_param(&query, "state"), query_param(&query, "code")) {
+            (Some(received), _) if received != state => continue,
+            (Some(_), Some(code)) => ("Logged in, you can close this page and go back to rippify.", Ok(code)),
+            _ => (
+                "Login failed, check the terminal for details.",
+                Err(query_param(&query, "error").unwrap_or("no code received".to_owned())),
+            ),
+        };
+
+        stream
+            .write_all(
+                format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: text/plain\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    body.len(),
+                    body
+                )
+                .as_bytes(),
+            )
+            .await?;
+
+        break result?;
+    };
+
+    let response = reqwest::Client::new()
+        .post("https://accounts.spotify.com/api/token")
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("code", &code),
+            ("redirect_uri", REDIRECT_URI),
+            ("client_id", client_id),
+            ("code_verifier", &verifier),
+        ])
+        .send()
+        .await?
+        .error_for_status()?
+        .json::<TokenResponse>()
+        .await?;
+
+    Ok(response.access_token)
+}
+
+// The browser asks for "GET /login?code=...&state=... HTTP/1.1"; anything else (like a favicon) is ignored
+fn redirect_query(request_line: &str) -> Option<String> {
+    let target = request_line.strip_prefix("GET ")?.split(' ').next()?;
+    let (path, query) = target.split_once('?')?;
+
+    (path == "/login").then(|| query.to_owned())
+}
+
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query
+        .split('&')
+        .filter_map(|pair| pair.split_once('='))
+        .find(|(key, _)| *key == name)
+        .and_then(|(_, value)| urlencoding::decode(value).ok())
+        .map(|value| value.into_owned())
+}
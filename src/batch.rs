@@ -0,0 +1,149 @@
+use serde::{Deserialize, Serialize};
+use std::collections as coll;
+use std::fs;
+use std::io;
+use std::path;
+use std::time;
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "lowercase", tag = "status", content = "reason")]
+pub enum TrackResult {
+    Done,
+    Existing,
+    Blocked,
+    Failed(String),
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct BatchState {
+    pub created: u64,
+    pub updated: u64,
+    pub inputs: Vec<String>,
+    pub queue: Vec<String>,
+    pub results: coll::BTreeMap<String, TrackResult>,
+}
+
+// A named batch persisted under the user's data directory, so an interrupted run can pick up where it left off
+pub struct Batch {
+    pub name: String,
+    pub state: BatchState,
+    path: path::PathBuf,
+}
+
+impl Batch {
+    pub fn dir() -> io::Result<path::PathBuf> {
+        dirs::data_dir()
+            .map(|dir| dir.join("rippify").join("sessions"))
+            .ok_or(io::Error::new(
+                io::ErrorKind::NotFound,
+                "cannot find user data directory",
+            ))
+    }
+
+    pub fn open(name: &str) -> io::Result<Batch> {
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+            || name.starts_with('.')
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid session name \"{}\"", name),
+            ));
+        }
+
+        let path = Batch::dir()?.join(name);
+
+        if path.join("session.json").exists() {
+            return Batch::load(&path);
+        }
+
+        fs::create_dir_all(&path)?;
+
+        let mut batch = Batch {
+            name: name.to_owned(),
+            state: BatchState {
+                created: now(),
+                updated: now(),
+                ..Default::default()
+            },
+            path,
+        };
+
+        batch.save()?;
+        Ok(batch)
+    }
+
+    fn load(path: &path::Path) -> io::Result<Batch> {
+        let file = fs::File::open(path.join("session.json"))?;
+
+        Ok(Batch {
+            name: path.file_name().unwrap_or_default().to_string_lossy().into_owned(),
+            state: serde_json::from_reader(io::BufReader::new(file))?,
+            path: path.to_owned(),
+        })
+    }
+
+    pub fn list() -> io::Result<Vec<Batch>> {
+        let dir = Batch::dir()?;
+
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let mut batches = fs::read_dir(dir)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().join("session.json").exists())
+            .filter_map(|entry| Batch::load(&entry.path()).ok())
+            .collect::<Vec<_>>();
+
+        batches.sort_by_key(|batch| batch.state.created);
+        Ok(batches)
+    }
+
+    pub fn is_resolved(&self) -> bool {
+        !self.state.queue.is_empty()
+    }
+
+    pub fn set_queue(&mut self, inputs: &[String], queue: Vec<String>) -> io::Result<()> {
+        self.state.inputs = inputs.to_vec();
+        self.state.queue = queue;
+        self.save()
+    }
+
+    pub fn is_finished(&self, id: &str) -> bool {
+        matches!(
+            self.state.results.get(id),
+            Some(TrackResult::Done) | Some(TrackResult::Existing) | Some(TrackResult::Blocked)
+        )
+    }
+
+    pub fn count(&self, result: fn(&TrackResult) -> bool) -> usize {
+        self.state.results.values().filter(|r| result(r)).count()
+    }
+
+    pub fn record(&mut self, id: &str, result: TrackResult) -> io::Result<()> {
+        self.state.results.insert(id.to_owned(), result);
+        self.save()
+    }
+
+    fn save(&mut self) -> io::Result<()> {
+        self.state.updated = now();
+
+        let mut state = serde_json::to_vec_pretty(&self.state)?;
+        state.push(b'\n');
+
+        let tmp_path = self.path.join("session.json.tmp");
+        fs::write(&tmp_path, state)?;
+        fs::rename(tmp_path, self.path.join("session.json"))
+    }
+}
+
+fn now() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,94 @@
+use librespot_core as lsc;
+use librespot_metadata as lsm;
+use rippify::resource::{get_resource_from_line, ResourceKind};
+use std::collections as coll;
+use std::fs;
+use std::io;
+
+// Tracks that are never downloaded, whatever the input. Each line is a track link, uri or id, or an ISRC;
+// blank lines and lines starting with # are skipped. Ids are kept without their item type, which bare ids don't have
+#[derive(Default)]
+pub struct Blocklist {
+    ids: coll::HashSet<u128>,
+    isrcs: coll::HashSet<String>,
+}
+
+impl Blocklist {
+    pub fn load(path: &str) -> io::Result<Blocklist> {
+        Blocklist::parse(&fs::read_to_string(path)?)
+    }
+
+    fn parse(contents: &str) -> io::Result<Blocklist> {
+        let mut blocklist = Blocklist::default();
+        let isrc_regex = regex::Regex::new(r"^[A-Z]{2}[A-Z0-9]{3}\d{7}$").unwrap();
+
+        for (num, line) in contents.lines().enumerate() {
+            let line = line.trim();
+
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let isrc = line.replace('-', "").to_uppercase();
+
+            if let Some(id) = get_resource_from_line(line)
+                .ok()
+                .filter(|res| matches!(res.kind, ResourceKind::Track))
+                .map(|res| res.id)
+            {
+                blocklist.ids.insert(id.id);
+            //
+            } else if let Ok(id) = lsc::SpotifyId::from_base62(line) {
+                blocklist.ids.insert(id.id);
+            //
+            } else if isrc_regex.is_match(&isrc) {
+                blocklist.isrcs.insert(isrc);
+            //
+            } else {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("line {}: \"{}\" is not a track or an ISRC", num + 1, line),
+                ));
+            }
+        }
+
+        Ok(blocklist)
+    }
+
+    pub fn contains_id(&self, id: &lsc::SpotifyId) -> bool {
+        self.ids.contains(&id.id)
+    }
+
+    // Catches the same recording published under another id too, as long as the ISRC matches
+    pub fn contains_track(&self, track: &lsm::Track) -> bool {
+        self.ids.contains(&track.id.id)
+            || track.external_ids.iter().any(|external_id| {
+                external_id.external_type == "isrc" && self.isrcs.contains(&external_id.id.to_uppercase())
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_ids_whatever_their_item_type() {
+        let blocklist =
+            Blocklist::parse("# covers\nspotify:track:4uLU6hMCjMI75M1A2tKUQC\n1301WleyT98MSxVHPZCA6M\n").unwrap();
+
+        let track = lsc::SpotifyId::from_uri("spotify:track:1301WleyT98MSxVHPZCA6M").unwrap();
+        let bare = lsc::SpotifyId::from_base62("4uLU6hMCjMI75M1A2tKUQC").unwrap();
+        let other = lsc::SpotifyId::from_uri("spotify:track:6rqhFgbbKwnb9MLmUQDhG6").unwrap();
+
+        assert!(blocklist.contains_id(&track));
+        assert!(blocklist.contains_id(&bare));
+        assert!(!blocklist.contains_id(&other));
+    }
+
+    #[test]
+    fn rejects_unknown_lines() {
+        assert!(Blocklist::parse("not a track").is_err());
+        assert!(Blocklist::parse("USRC17607839\n\n").is_ok());
+    }
+}
@@ -0,0 +1,56 @@
+use librespot_metadata as lsm;
+use lsm::artist::ArtistRole;
+
+// A movement of a larger work, as classical releases name their tracks: "Symphony No. 5 in C Minor, Op. 67: I. Allegro
+// con brio". That's the only place the structure shows up, Spotify has no fields for it
+pub struct Movement {
+    pub work: String,
+    pub number: u32,
+    pub name: String,
+}
+
+impl Movement {
+    pub fn from_name(name: &str) -> Option<Movement> {
+        let (work, movement) = name.rsplit_once(": ")?;
+        let (numeral, name) = movement.split_once(". ")?;
+
+        Some(Movement {
+            work: work.trim().to_owned(),
+            number: roman_number(numeral)?,
+            name: name.trim().to_owned(),
+        })
+        .filter(|movement| !movement.work.is_empty() && !movement.name.is_empty())
+    }
+}
+
+pub fn track_composers(track: &lsm::Track) -> Vec<&lsm::artist::ArtistWithRole> {
+    track
+        .artists_with_role
+        .iter()
+        .filter(|artist| artist.role == ArtistRole::ARTIST_ROLE_COMPOSER)
+        .collect()
+}
+
+// Movements don't go much past a dozen, so up to XXXIX is plenty
+fn roman_number(numeral: &str) -> Option<u32> {
+    let mut number = 0;
+    let mut last = 0;
+
+    for c in numeral.chars().rev() {
+        let value = match c {
+            'I' => 1,
+            'V' => 5,
+            'X' => 10,
+            _ => return None,
+        };
+
+        if value < last {
+            number -= value;
+        } else {
+            number += value;
+            last = value;
+        }
+    }
+
+    Some(number).filter(|number| *number > 0)
+}
@@ -0,0 +1,34 @@
+use serde::Deserialize;
+use std::collections as coll;
+use std::fs;
+use std::path;
+
+// Defaults for options that would otherwise have to be repeated on every run. Options given on the command line
+// always win over the ones in here
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub user: Option<String>,
+    pub format: Option<String>,
+    pub output_dir: Option<String>,
+    pub quality: Option<String>,
+    pub jobs: Option<usize>,
+    pub device_id: Option<String>,
+    pub client_id: Option<String>,
+    pub canonical_artists: Option<bool>,
+    pub sort_articles: Option<Vec<String>>,
+    // Artist ids or names, and the name to file them under instead
+    pub artist_aliases: coll::HashMap<String, String>,
+    // Artist or album names, and what {author_sort} and {album_sort} give for them
+    pub sort_names: coll::HashMap<String, String>,
+}
+
+impl Config {
+    pub fn default_path() -> Option<path::PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("rippify").join("config.toml"))
+    }
+
+    pub fn load(path: &path::Path) -> Result<Config, Box<dyn std::error::Error>> {
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+}
@@ -0,0 +1,71 @@
+use librespot_core as lsc;
+use librespot_metadata as lsm;
+use lsm::image::{Image, ImageSize};
+use std::fs;
+use std::path;
+
+// Albums list their cover in a few sizes, from 64px thumbnails up. Largest is whatever the biggest one is, which is
+// also what's used when the album doesn't have the size asked for
+#[derive(Clone, Copy, PartialEq)]
+pub enum CoverSize {
+    Small,
+    Medium,
+    Large,
+    XLarge,
+    Largest,
+}
+
+impl CoverSize {
+    pub fn from_name(size: &str) -> Option<CoverSize> {
+        match size {
+            "small" => Some(CoverSize::Small),
+            "medium" => Some(CoverSize::Medium),
+            "large" => Some(CoverSize::Large),
+            "xlarge" => Some(CoverSize::XLarge),
+            "largest" => Some(CoverSize::Largest),
+            _ => None,
+        }
+    }
+
+    fn image_size(&self) -> Option<ImageSize> {
+        match self {
+            CoverSize::Small => Some(ImageSize::SMALL),
+            CoverSize::Medium => Some(ImageSize::DEFAULT),
+            CoverSize::Large => Some(ImageSize::LARGE),
+            CoverSize::XLarge => Some(ImageSize::XLARGE),
+            CoverSize::Largest => None,
+        }
+    }
+
+    pub fn pick<'a>(&self, covers: &'a [Image]) -> Option<&'a Image> {
+        self.image_size()
+            .and_then(|size| covers.iter().find(|cover| cover.size == size))
+            .or_else(|| covers.iter().max_by_key(|cover| cover.width * cover.height))
+    }
+}
+
+// Written once per folder, a cover already there is left alone. Returns whether one was written
+pub async fn write_cover(
+    session: &lsc::Session,
+    album: &lsm::Album,
+    size: CoverSize,
+    dir: &path::Path,
+) -> Result<bool, Box<dyn std::error::Error>> {
+    let cover_file = dir.join("cover.jpg");
+
+    if cover_file.exists() {
+        return Ok(false);
+    }
+
+    let Some(cover) = size.pick(&album.covers) else {
+        return Ok(false);
+    };
+
+    let image = session.spclient().get_image(&cover.id).await?;
+
+    let part_file = dir.join("cover.jpg.part");
+    fs::write(&part_file, &image)?;
+    fs::rename(&part_file, &cover_file)?;
+
+    Ok(true)
+}
@@ -0,0 +1,532 @@
+use crate::error::{ProcessError, ProcessErrorKind};
+use crate::metadata::MetadataCache;
+use librespot_audio as lsa;
+use librespot_core as lsc;
+use librespot_metadata as lsm;
+use librespot_metadata::audio as lsm_audio;
+use std::collections as coll;
+use std::fmt;
+use std::io;
+use std::io::Read;
+use std::sync;
+use std::time;
+
+pub enum TrackDownloadErrorKind {
+    AudioKey,
+    AudioFile,
+    TrackFile,
+    InvalidAudio,
+    Timeout,
+    Unavailable,
+}
+
+impl ProcessErrorKind for TrackDownloadErrorKind {}
+
+impl TrackDownloadErrorKind {
+    // Everything but a refusal from the server can be a dropped connection or a bad transfer, worth another try
+    pub fn is_retryable(&self) -> bool {
+        !matches!(self, TrackDownloadErrorKind::Unavailable)
+    }
+}
+pub type TrackDownloadError = ProcessError<TrackDownloadErrorKind>;
+
+// Decrypted audio files start with a header of Spotify's own, followed by the actual ogg stream
+const SPOTIFY_HEADER_LEN: usize = 0xa7;
+
+#[derive(Clone, Copy, PartialEq, PartialOrd)]
+pub enum AudioQuality {
+    Low,
+    Normal,
+    High,
+}
+
+impl AudioQuality {
+    pub fn from_name(quality: &str) -> Option<AudioQuality> {
+        match quality {
+            "low" => Some(AudioQuality::Low),
+            "normal" => Some(AudioQuality::Normal),
+            "high" => Some(AudioQuality::High),
+            _ => None,
+        }
+    }
+
+    pub fn from_format(format: &lsm_audio::AudioFileFormat) -> Option<AudioQuality> {
+        match format {
+            lsm_audio::AudioFileFormat::OGG_VORBIS_96 => Some(AudioQuality::Low),
+            lsm_audio::AudioFileFormat::OGG_VORBIS_160 => Some(AudioQuality::Normal),
+            lsm_audio::AudioFileFormat::OGG_VORBIS_320 => Some(AudioQuality::High),
+            _ => None,
+        }
+    }
+
+    pub fn format(&self) -> lsm_audio::AudioFileFormat {
+        match self {
+            AudioQuality::Low => lsm_audio::AudioFileFormat::OGG_VORBIS_96,
+            AudioQuality::Normal => lsm_audio::AudioFileFormat::OGG_VORBIS_160,
+            AudioQuality::High => lsm_audio::AudioFileFormat::OGG_VORBIS_320,
+        }
+    }
+
+    pub fn kbps(&self) -> u32 {
+        match self {
+            AudioQuality::Low => 96,
+            AudioQuality::Normal => 160,
+            AudioQuality::High => 320,
+        }
+    }
+
+    // The requested quality first, then lower ones, and only then anything better
+    fn preference(&self) -> Vec<AudioQuality> {
+        match self {
+            AudioQuality::Low => vec![AudioQuality::Low, AudioQuality::Normal, AudioQuality::High],
+            AudioQuality::Normal => vec![AudioQuality::Normal, AudioQuality::Low, AudioQuality::High],
+            AudioQuality::High => vec![AudioQuality::High, AudioQuality::Normal, AudioQuality::Low],
+        }
+    }
+}
+
+// Fetches the audio of tracks, giving up on downloads that stall for longer than `timeout` when one is set.
+// With `strict` set, tracks that aren't available at `quality` fail instead of falling back to another bitrate
+pub struct TrackDownloader {
+    session: lsc::Session,
+    timeout: Option<time::Duration>,
+    quality: AudioQuality,
+    strict: bool,
+    rate_limit: Option<sync::Arc<RateLimit>>,
+}
+
+impl TrackDownloader {
+    pub fn new(
+        session: lsc::Session,
+        timeout: Option<time::Duration>,
+        quality: AudioQuality,
+        strict: bool,
+    ) -> TrackDownloader {
+        TrackDownloader {
+            session,
+            timeout,
+            quality,
+            strict,
+            rate_limit: None,
+        }
+    }
+
+    // Caps how fast audio is read, in bytes per second, across all the tracks downloading at the same time
+    pub fn with_rate_limit(mut self, bytes_per_sec: Option<u64>) -> TrackDownloader {
+        self.rate_limit = bytes_per_sec.map(|bytes_per_sec| {
+            sync::Arc::new(RateLimit {
+                bytes_per_sec,
+                next_slot: sync::Mutex::new(time::Instant::now()),
+            })
+        });
+        self
+    }
+
+    // Picks the ogg file closest to the requested quality, from the track or the first of its alternatives that has one
+    pub async fn get_track(
+        &self,
+        id: &lsc::SpotifyId,
+        metadata_cache: &MetadataCache,
+    ) -> Result<(lsm::Track, lsc::FileId), librespot_core::error::Error> {
+        let mut track_ids = coll::VecDeque::<lsc::SpotifyId>::new();
+        track_ids.push_back(id.to_owned());
+
+        let qualities = if self.strict {
+            vec![self.quality]
+        } else {
+            self.quality.preference()
+        };
+
+        while let Some(id) = track_ids.pop_front() {
+            let track = metadata_cache.track(&self.session, &id).await?;
+
+            match qualities.iter().find_map(|quality| track.files.get(&quality.format())) {
+                Some(file_id) => return Ok((track.to_owned(), file_id.to_owned())),
+                None => track_ids.extend(track.alternatives.0),
+            };
+        }
+
+        if self.strict {
+            return Err(librespot_core::error::Error::not_found(format!(
+                "track is not available at {} kbps",
+                self.quality.kbps()
+            )));
+        }
+
+        Err(librespot_core::error::Error::not_found("cannot find a suitable track"))
+    }
+
+    pub async fn download(
+        &self,
+        track: &lsm::Track,
+        file_id: &lsc::FileId,
+        progress: Option<&indicatif::ProgressBar>,
+    ) -> Result<(AudioContainer, Vec<u8>), TrackDownloadError> {
+        self.download_with(track, file_id, progress, |container, audio| {
+            let mut buffer = Vec::<u8>::new();
+            audio.read_to_end(&mut buffer).map(|_| (container, buffer))
+        })
+        .await?
+        .map_err(|e| ProcessError {
+            kind: TrackDownloadErrorKind::TrackFile,
+            error: e.into(),
+        })
+    }
+
+    // Hands the decrypted audio to `consume` while it comes in, so it can be processed and written out without ever
+    // holding the whole file. Whatever `consume` returns is given back as is, its own errors included
+    pub async fn download_with<T, E, F>(
+        &self,
+        track: &lsm::Track,
+        file_id: &lsc::FileId,
+        progress: Option<&indicatif::ProgressBar>,
+        consume: F,
+    ) -> Result<Result<T, E>, TrackDownloadError>
+    where
+        T: Send + 'static,
+        E: Send + 'static,
+        F: FnOnce(AudioContainer, &mut dyn io::Read) -> Result<T, E> + Send + 'static,
+    {
+        let track_file_key = with_stall_timeout(self.timeout, self.session.audio_key().request(track.id, *file_id))
+            .await?
+            .map_err(|e| ProcessError {
+                kind: match e.kind {
+                    lsc::error::ErrorKind::NotFound | lsc::error::ErrorKind::PermissionDenied => {
+                        TrackDownloadErrorKind::Unavailable
+                    }
+                    _ => TrackDownloadErrorKind::AudioKey,
+                },
+                error: e.into(),
+            })?;
+
+        let track_file_audio = with_stall_timeout(self.timeout, lsa::AudioFile::open(&self.session, *file_id, 40))
+            .await?
+            .map_err(|e| ProcessError {
+                kind: TrackDownloadErrorKind::AudioFile,
+                error: e.into(),
+            })?;
+
+        if let (Some(progress), Ok(controller)) = (progress, track_file_audio.get_stream_loader_controller()) {
+            progress.set_length(controller.len() as u64);
+        }
+
+        track_stream_audio_file(
+            track_file_audio,
+            track_file_key,
+            self.timeout,
+            progress.cloned(),
+            self.rate_limit.clone(),
+            consume,
+        )
+        .await
+    }
+
+    // Previews are 30 second clips served unencrypted, no audio key or audio file channel is needed for them
+    pub async fn download_preview(&self, track: &lsm::Track) -> Result<(AudioContainer, Vec<u8>), TrackDownloadError> {
+        let file_id = track.previews.values().next().ok_or(ProcessError {
+            kind: TrackDownloadErrorKind::Unavailable,
+            error: "track has no preview".into(),
+        })?;
+
+        let url = format!(
+            "https://p.scdn.co/mp3-preview/{}",
+            file_id.to_base16().map_err(|e| ProcessError {
+                kind: TrackDownloadErrorKind::AudioFile,
+                error: e.into(),
+            })?
+        );
+
+        let buffer = with_stall_timeout(self.timeout, async {
+            reqwest::get(url).await?.error_for_status()?.bytes().await
+        })
+        .await?
+        .map_err(|e| ProcessError {
+            kind: match e.status() {
+                Some(status) if status.is_client_error() => TrackDownloadErrorKind::Unavailable,
+                _ => TrackDownloadErrorKind::TrackFile,
+            },
+            error: e.into(),
+        })?;
+
+        match AudioContainer::detect(&buffer) {
+            Some(container) => Ok((container, buffer.to_vec())),
+            None => Err(ProcessError {
+                kind: TrackDownloadErrorKind::TrackFile,
+                error: "preview is not in any known audio container".into(),
+            }),
+        }
+    }
+}
+
+pub fn track_file_audio_quality(track: &lsm::Track, file_id: &lsc::FileId) -> Option<AudioQuality> {
+    track
+        .files
+        .iter()
+        .find(|(_, id)| *id == file_id)
+        .and_then(|(format, _)| AudioQuality::from_format(format))
+}
+
+pub fn track_file_quality(track: &lsm::Track, file_id: &lsc::FileId) -> String {
+    track
+        .files
+        .iter()
+        .find(|(_, id)| *id == file_id)
+        .map(|(format, _)| format!("{:?}", format))
+        .unwrap_or_default()
+}
+
+// Waits twice as long after every failed attempt, from 1s up to a minute. A random part of up to half of it is added
+// so parallel downloads that failed together don't all retry at the same moment
+pub fn retry_delay(attempt: u32) -> time::Duration {
+    let delay = time::Duration::from_secs(1 << attempt.min(6)).min(time::Duration::from_secs(60));
+    delay.mul_f64(1.0 + rand::random::<f64>() / 2.0)
+}
+
+async fn with_stall_timeout<T>(
+    timeout: Option<time::Duration>,
+    future: impl std::future::Future<Output = T>,
+) -> Result<T, TrackDownloadError> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future).await.map_err(|_| ProcessError {
+            kind: TrackDownloadErrorKind::Timeout,
+            error: format!("no response in {}s", timeout.as_secs()).into(),
+        }),
+        None => Ok(future.await),
+    }
+}
+
+// Counts what came in, for the stall timeout and the progress bar. Consumers of the audio turn read errors into
+// errors of their own, so the first one is also kept here to tell that it was the download that failed
+struct AudioFileReader {
+    file: lsa::AudioFile,
+    progress: sync::Arc<sync::atomic::AtomicUsize>,
+    progress_bar: Option<indicatif::ProgressBar>,
+    rate_limit: Option<sync::Arc<RateLimit>>,
+    // Set when the download is given up on, so the thread left behind stops at its next read
+    cancelled: sync::Arc<sync::atomic::AtomicBool>,
+    read_len: usize,
+    error: Option<io::Error>,
+}
+
+// Every read is given the next free slot of time its length takes at the limit, and waits for it. Reads run on their
+// own threads, so blocking them is fine
+struct RateLimit {
+    bytes_per_sec: u64,
+    next_slot: sync::Mutex<time::Instant>,
+}
+
+impl RateLimit {
+    fn wait(&self, len: usize) {
+        let wait = {
+            let mut next_slot = self.next_slot.lock().unwrap();
+            let now = time::Instant::now();
+            let slot = (*next_slot).max(now);
+
+            *next_slot = slot + time::Duration::from_secs_f64(len as f64 / self.bytes_per_sec as f64);
+            slot - now
+        };
+
+        std::thread::sleep(wait);
+    }
+}
+
+impl io::Read for AudioFileReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.cancelled.load(sync::atomic::Ordering::Relaxed) {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, "download was given up on"));
+        }
+
+        match self.file.read(buf) {
+            Ok(_) if self.cancelled.load(sync::atomic::Ordering::Relaxed) => {
+                Err(io::Error::new(io::ErrorKind::TimedOut, "download was given up on"))
+            }
+            Ok(len) => {
+                self.read_len += len;
+                self.progress.store(self.read_len, sync::atomic::Ordering::Relaxed);
+
+                if let Some(progress_bar) = &self.progress_bar {
+                    progress_bar.set_position(self.read_len as u64);
+                }
+
+                if let Some(rate_limit) = &self.rate_limit {
+                    rate_limit.wait(len);
+                }
+
+                Ok(len)
+            }
+            Err(err) => {
+                let copy = io::Error::new(err.kind(), err.to_string());
+                self.error.get_or_insert(err);
+                Err(copy)
+            }
+        }
+    }
+}
+
+// Reading the audio file blocks until each chunk arrives, so it runs on its own thread while we keep an eye on
+// how many bytes came in. A stalled read can't be interrupted, so the thread is left behind when giving up on it,
+// and it fails as soon as that read returns
+async fn track_stream_audio_file<T, E, F>(
+    file: lsa::AudioFile,
+    key: lsc::audio_key::AudioKey,
+    timeout: Option<time::Duration>,
+    progress_bar: Option<indicatif::ProgressBar>,
+    rate_limit: Option<sync::Arc<RateLimit>>,
+    consume: F,
+) -> Result<Result<T, E>, TrackDownloadError>
+where
+    T: Send + 'static,
+    E: Send + 'static,
+    F: FnOnce(AudioContainer, &mut dyn io::Read) -> Result<T, E> + Send + 'static,
+{
+    let progress = sync::Arc::new(sync::atomic::AtomicUsize::new(0));
+    let cancelled = sync::Arc::new(sync::atomic::AtomicBool::new(false));
+
+    let mut source = AudioFileReader {
+        file,
+        progress: progress.clone(),
+        progress_bar,
+        rate_limit,
+        cancelled: cancelled.clone(),
+        read_len: 0,
+        error: None,
+    };
+
+    let mut reader = tokio::task::spawn_blocking(move || {
+        let result = track_decrypt_audio(&mut source, key, consume);
+
+        match source.error.take() {
+            Some(err) => Err((TrackDownloadErrorKind::TrackFile, err.into())),
+            None => result,
+        }
+    });
+
+    let mut last_progress = 0;
+    let mut last_progress_at = time::Instant::now();
+
+    loop {
+        tokio::select! {
+            result = &mut reader => {
+                return match result {
+                    Ok(result) => result.map_err(|(kind, error)| ProcessError { kind, error }),
+                    Err(err) => Err(ProcessError {
+                        kind: TrackDownloadErrorKind::TrackFile,
+                        error: err.into(),
+                    }),
+                };
+            }
+            _ = tokio::time::sleep(time::Duration::from_secs(1)), if timeout.is_some() => {
+                let current = progress.load(sync::atomic::Ordering::Relaxed);
+
+                if current != last_progress {
+                    last_progress = current;
+                    last_progress_at = time::Instant::now();
+                } else if timeout.is_some_and(|timeout| last_progress_at.elapsed() >= timeout) {
+                    cancelled.store(true, sync::atomic::Ordering::Relaxed);
+
+                    return Err(ProcessError {
+                        kind: TrackDownloadErrorKind::Timeout,
+                        error: format!(
+                            "no data received in {}s after {} bytes",
+                            last_progress_at.elapsed().as_secs(),
+                            current
+                        )
+                        .into(),
+                    });
+                }
+            }
+        }
+    }
+}
+
+type StreamError = (TrackDownloadErrorKind, Box<dyn std::error::Error + Send + Sync>);
+
+// Only ogg vorbis files are ever picked, so a stream that isn't ogg after Spotify's own header means the decryption
+// went wrong. The header is left out of what `consume` gets
+fn track_decrypt_audio<T, E, F>(
+    source: &mut AudioFileReader,
+    key: lsc::audio_key::AudioKey,
+    consume: F,
+) -> Result<Result<T, E>, StreamError>
+where
+    F: FnOnce(AudioContainer, &mut dyn io::Read) -> Result<T, E>,
+{
+    let mut decrypted = io::BufReader::with_capacity(0x10000, lsa::AudioDecrypt::new(Some(key), source));
+
+    let mut head = Vec::<u8>::new();
+    (&mut decrypted)
+        .take(SPOTIFY_HEADER_LEN as u64 + 4)
+        .read_to_end(&mut head)
+        .map_err(|e| (TrackDownloadErrorKind::TrackFile, e.into()))?;
+
+    if !head
+        .get(SPOTIFY_HEADER_LEN..)
+        .is_some_and(|audio| audio.starts_with(b"OggS"))
+    {
+        return Err((
+            TrackDownloadErrorKind::InvalidAudio,
+            "decrypted stream is not an ogg stream".into(),
+        ));
+    }
+
+    let head = head.split_off(SPOTIFY_HEADER_LEN);
+
+    Ok(consume(
+        AudioContainer::Ogg,
+        &mut io::Cursor::new(head).chain(decrypted),
+    ))
+}
+
+// Decrypts `len` bytes of silence with a made up key, to time decryption on its own. In a download it only runs as
+// fast as the audio comes in
+pub fn time_decrypt(len: u64) -> io::Result<time::Duration> {
+    let started = time::Instant::now();
+    let mut decrypted = lsa::AudioDecrypt::new(Some(lsc::audio_key::AudioKey([0; 16])), io::repeat(0).take(len));
+
+    io::copy(&mut decrypted, &mut io::sink())?;
+    Ok(started.elapsed())
+}
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum AudioContainer {
+    Ogg,
+    Mp3,
+    Mp4,
+    Flac,
+}
+
+impl fmt::Display for AudioContainer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AudioContainer::Ogg => write!(f, "ogg vorbis"),
+            AudioContainer::Mp3 => write!(f, "mp3"),
+            AudioContainer::Mp4 => write!(f, "mp4 aac"),
+            AudioContainer::Flac => write!(f, "flac"),
+        }
+    }
+}
+
+impl AudioContainer {
+    pub fn detect(buffer: &[u8]) -> Option<AudioContainer> {
+        if buffer.starts_with(b"OggS") {
+            Some(AudioContainer::Ogg)
+        } else if buffer.starts_with(b"fLaC") {
+            Some(AudioContainer::Flac)
+        } else if buffer.starts_with(b"ID3") || (buffer.len() > 1 && buffer[0] == 0xff && buffer[1] & 0xe0 == 0xe0) {
+            Some(AudioContainer::Mp3)
+        } else if buffer.get(4..8) == Some(b"ftyp") {
+            Some(AudioContainer::Mp4)
+        } else {
+            None
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioContainer::Ogg => "ogg",
+            AudioContainer::Mp3 => "mp3",
+            AudioContainer::Mp4 => "m4a",
+            AudioContainer::Flac => "flac",
+        }
+    }
+}
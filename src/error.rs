@@ -0,0 +1,7 @@
+pub trait ProcessErrorKind {}
+
+// An error at one of the steps of processing a track, `kind` telling which one
+pub struct ProcessError<T: ProcessErrorKind> {
+    pub kind: T,
+    pub error: Box<dyn std::error::Error + Send + Sync>,
+}
@@ -0,0 +1,250 @@
+use crate::matching;
+use crate::resource::{InputResource, ResourceKind};
+use crate::search;
+use librespot_core as lsc;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::fmt;
+
+pub enum ExternalService {
+    Deezer,
+    AppleMusic,
+    YoutubeMusic,
+}
+
+impl fmt::Display for ExternalService {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExternalService::Deezer => write!(f, "deezer"),
+            ExternalService::AppleMusic => write!(f, "apple music"),
+            ExternalService::YoutubeMusic => write!(f, "youtube music"),
+        }
+    }
+}
+
+pub struct ExternalLink {
+    pub service: ExternalService,
+    pub kind: ResourceKind,
+    pub id: String,
+}
+
+// What we could learn about the linked item; `code` is the ISRC for tracks and the UPC for albums
+struct ExternalItem {
+    code: Option<String>,
+    artist: String,
+    title: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct DeezerArtist {
+    name: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct DeezerItem {
+    title: String,
+    isrc: Option<String>,
+    upc: Option<String>,
+    artist: DeezerArtist,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ItunesItem {
+    #[serde(rename = "trackName")]
+    track_name: Option<String>,
+    #[serde(rename = "collectionName")]
+    collection_name: Option<String>,
+    #[serde(rename = "artistName")]
+    artist_name: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ItunesLookup {
+    results: Vec<ItunesItem>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct YoutubeOembed {
+    title: String,
+    author_name: String,
+}
+
+impl ExternalLink {
+    pub fn from_line(line: &str) -> Option<ExternalLink> {
+        let deezer = regex::Regex::new(r"^(https?://)?(www\.)?deezer\.com/([a-z]{2}/)?(track|album)/(\d+)").unwrap();
+        let apple = regex::Regex::new(r"^(https?://)?music\.apple\.com/[a-z]{2}/(album|song)/[^/?]+/(\d+)(\?i=(\d+))?")
+            .unwrap();
+        let youtube = regex::Regex::new(r"^(https?://)?music\.youtube\.com/watch\?v=([[:word:]-]{11})").unwrap();
+
+        if let Some(captures) = deezer.captures(line) {
+            Some(ExternalLink {
+                service: ExternalService::Deezer,
+                kind: if &captures[4] == "track" {
+                    ResourceKind::Track
+                } else {
+                    ResourceKind::Album
+                },
+                id: captures[5].to_owned(),
+            })
+        //
+        } else if let Some(captures) = apple.captures(line) {
+            if let Some(song) = captures.get(5) {
+                Some(ExternalLink {
+                    service: ExternalService::AppleMusic,
+                    kind: ResourceKind::Track,
+                    id: song.as_str().to_owned(),
+                })
+            } else {
+                Some(ExternalLink {
+                    service: ExternalService::AppleMusic,
+                    kind: if &captures[2] == "song" {
+                        ResourceKind::Track
+                    } else {
+                        ResourceKind::Album
+                    },
+                    id: captures[3].to_owned(),
+                })
+            }
+        //
+        } else {
+            youtube.captures(line).map(|captures| ExternalLink {
+                service: ExternalService::YoutubeMusic,
+                kind: ResourceKind::Track,
+                id: captures[2].to_owned(),
+            })
+        }
+    }
+
+    pub async fn resolve(&self, session: &lsc::Session) -> Result<InputResource, lsc::Error> {
+        let item = match self.service {
+            ExternalService::Deezer => self.get_deezer_item().await?,
+            ExternalService::AppleMusic => self.get_apple_item().await?,
+            ExternalService::YoutubeMusic => self.get_youtube_item().await?,
+        };
+
+        // A hit for the ISRC/UPC is the same recording or release, it's taken as is
+        if let Some(code) = &item.code {
+            let query = match self.kind {
+                ResourceKind::Album => format!("upc:{}", code),
+                _ => format!("isrc:{}", code),
+            };
+            let results = search::search(session, &query, 5).await?;
+
+            let uri = match self.kind {
+                ResourceKind::Album => results.albums.hits.first().map(|hit| hit.uri.clone()),
+                _ => results.tracks.hits.first().map(|hit| hit.uri.clone()),
+            };
+
+            if let Some(uri) = uri {
+                return self.resource(&uri);
+            }
+        }
+
+        // Searching by artist and title can return anything, the best scoring hit has to be close enough
+        let results = search::search(session, &format!("{} {}", item.artist, item.title), 5).await?;
+
+        let best = match self.kind {
+            ResourceKind::Album => results
+                .albums
+                .hits
+                .iter()
+                .map(|hit| (matching::album_confidence(&item.artist, &item.title, hit), &hit.uri))
+                .max_by(|a, b| a.0.total_cmp(&b.0)),
+            _ => results
+                .tracks
+                .hits
+                .iter()
+                .map(|hit| {
+                    (
+                        matching::track_confidence(&item.artist, &item.title, None, hit),
+                        &hit.uri,
+                    )
+                })
+                .max_by(|a, b| a.0.total_cmp(&b.0)),
+        };
+
+        match best {
+            Some((confidence, uri)) if confidence >= matching::MIN_CONFIDENCE => self.resource(uri),
+            Some((confidence, uri)) => Err(lsc::Error::not_found(format!(
+                "best spotify match for \"{} - {}\" is {} with confidence {:.2}",
+                item.artist, item.title, uri, confidence
+            ))),
+            None => Err(lsc::Error::not_found(format!(
+                "no spotify match for \"{} - {}\"",
+                item.artist, item.title
+            ))),
+        }
+    }
+
+    fn resource(&self, uri: &str) -> Result<InputResource, lsc::Error> {
+        Ok(InputResource {
+            kind: self.kind.clone(),
+            id: lsc::SpotifyId::from_uri(uri)?,
+        })
+    }
+
+    async fn get_deezer_item(&self) -> Result<ExternalItem, lsc::Error> {
+        let item: DeezerItem = get_json(&format!("https://api.deezer.com/{}/{}", self.kind, self.id)).await?;
+
+        if item.title.is_empty() {
+            return Err(lsc::Error::not_found("deezer returned no metadata"));
+        }
+
+        Ok(ExternalItem {
+            code: item.isrc.or(item.upc),
+            artist: item.artist.name,
+            title: item.title,
+        })
+    }
+
+    async fn get_apple_item(&self) -> Result<ExternalItem, lsc::Error> {
+        let lookup: ItunesLookup = get_json(&format!("https://itunes.apple.com/lookup?id={}", self.id)).await?;
+        let item = lookup
+            .results
+            .into_iter()
+            .next()
+            .ok_or(lsc::Error::not_found("itunes returned no metadata"))?;
+
+        let title = match self.kind {
+            ResourceKind::Album => item.collection_name,
+            _ => item.track_name,
+        }
+        .unwrap_or_default();
+
+        Ok(ExternalItem {
+            code: None,
+            artist: item.artist_name,
+            title,
+        })
+    }
+
+    async fn get_youtube_item(&self) -> Result<ExternalItem, lsc::Error> {
+        let oembed: YoutubeOembed = get_json(&format!(
+            "https://www.youtube.com/oembed?format=json&url={}",
+            urlencoding::encode(&format!("https://music.youtube.com/watch?v={}", self.id))
+        ))
+        .await?;
+
+        // Auto-generated music uploads are attributed to an "<artist> - Topic" channel
+        Ok(ExternalItem {
+            code: None,
+            artist: oembed.author_name.trim_end_matches(" - Topic").to_owned(),
+            title: oembed.title,
+        })
+    }
+}
+
+async fn get_json<T: DeserializeOwned>(url: &str) -> Result<T, lsc::Error> {
+    reqwest::get(url)
+        .await
+        .and_then(|response| response.error_for_status())
+        .map_err(lsc::Error::unavailable)?
+        .json::<T>()
+        .await
+        .map_err(lsc::Error::failed_precondition)
+}
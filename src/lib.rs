@@ -0,0 +1,22 @@
+pub mod classical;
+pub mod cover;
+pub mod download;
+pub mod error;
+pub mod external;
+pub mod lyrics;
+pub mod manifest;
+pub mod matching;
+pub mod metadata;
+pub mod output;
+pub mod owned;
+pub mod resource;
+pub mod sanitize;
+pub mod search;
+pub mod tags;
+pub mod transcode;
+
+pub use download::{AudioContainer, AudioQuality, TrackDownloader};
+pub use librespot_core::{Session, SpotifyId};
+pub use metadata::{MetadataCache, MetadataProvider};
+pub use output::OutputFormat;
+pub use resource::{InputResource, ResourceKind, TrackGroup};
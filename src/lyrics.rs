@@ -0,0 +1,45 @@
+use librespot_core as lsc;
+use librespot_metadata as lsm;
+use lsm::lyrics::SyncType;
+
+// Lyrics as they are written out. Synced ones are kept in lrc form, each line after the [mm:ss.xx] it starts at, which
+// is also what players that show them in time look for in the lyrics tag
+pub struct TrackLyrics {
+    pub synced: bool,
+    pub text: String,
+}
+
+impl TrackLyrics {
+    pub async fn get(session: &lsc::Session, track: &lsm::Track) -> Result<Option<TrackLyrics>, lsc::Error> {
+        // Asking for lyrics a track doesn't have is an error, not an empty answer
+        if !track.has_lyrics {
+            return Ok(None);
+        }
+
+        let lyrics = lsm::Lyrics::get(session, &track.id).await?;
+        Ok(Some(TrackLyrics::from_lyrics(&lyrics)))
+    }
+
+    pub fn from_lyrics(lyrics: &lsm::Lyrics) -> TrackLyrics {
+        let synced = lyrics.lyrics.sync_type == SyncType::LineSynced;
+
+        let text = lyrics
+            .lyrics
+            .lines
+            .iter()
+            .map(|line| match line.start_time_ms.parse::<u64>() {
+                Ok(start) if synced => format!(
+                    "[{:02}:{:02}.{:02}]{}",
+                    start / 60_000,
+                    start / 1000 % 60,
+                    start % 1000 / 10,
+                    line.words
+                ),
+                _ => line.words.clone(),
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        TrackLyrics { synced, text }
+    }
+}
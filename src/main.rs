@@ -1,26 +1,69 @@
-use async_recursion::async_recursion;
 use colored::Colorize;
+use futures::future;
+use futures::stream::{self, StreamExt};
 use lewton::header as lhr;
-use librespot_audio as lsa;
 use librespot_core as lsc;
 use librespot_core::authentication as lsc_auth;
 use librespot_metadata as lsm;
 use librespot_metadata::audio as lsm_audio;
-use lsm::Metadata;
+use rippify::cover::{self, CoverSize};
+use rippify::download::{
+    retry_delay, time_decrypt, track_file_audio_quality, track_file_quality, AudioQuality, TrackDownloadErrorKind,
+    TrackDownloader,
+};
+use rippify::lyrics::TrackLyrics;
+use rippify::output::{
+    apply_modifier, reproducible_mtime, track_create_part, track_finish_part, track_part_file, ArtistNames,
+    AuthorPolicy, Featuring, OutputFile, OutputFormat, SortNames, TrackFields, TrackWriteError, TrackWriteErrorKind,
+    FORMAT_FIELDS, FORMAT_MODIFIERS,
+};
+use rippify::resource::{
+    get_resource_from_line, ArtistScope, InputResource, LibraryCollection, PlaylistKeyword, ResourceKind, TrackGroup,
+    UserProfile,
+};
+use rippify::sanitize::{SanitizePolicy, Sanitizer};
+use rippify::tags::{replace_header_comment, track_add_metadata_tags, track_comment_header, TagsWriteErrorKind};
+use rippify::transcode::{self, TranscodeErrorKind};
+use rippify::{external, manifest, matching, metadata, owned, search, AudioContainer};
+use std::cmp;
 use std::collections as coll;
 use std::env;
-use std::fmt;
 use std::fs;
 use std::io;
-use std::io::Read;
+use std::io::Write;
 use std::path;
 use std::process as proc;
+use std::sync;
+use std::time;
+
+mod archive;
+mod auth;
+mod batch;
+mod blocklist;
+mod config;
+mod pipe;
+mod playlist_sync;
+mod trash;
 
 static VERSION: &str = "0.2.0";
+static GIT_COMMIT: &str = env!("RIPPIFY_GIT_COMMIT");
+
+// With --json, stdout only carries the events and the messages meant to be read by people go to stderr
+static TEXT_TO_STDERR: sync::atomic::AtomicBool = sync::atomic::AtomicBool::new(false);
+
+macro_rules! say {
+    ($($arg:tt)*) => {
+        if TEXT_TO_STDERR.load(sync::atomic::Ordering::Relaxed) {
+            eprintln!($($arg)*);
+        } else {
+            println!($($arg)*);
+        }
+    };
+}
 
 #[tokio::main]
 async fn main() {
-    let opts = match parse_opts() {
+    let mut opts = match parse_opts() {
         Ok(opts) => opts,
         Err(err) => {
             println!("{}: {}", "error".red().bold(), err.to_string().bold());
@@ -28,17 +71,86 @@ async fn main() {
         }
     };
 
-    let credentials = lsc_auth::Credentials::with_password(&opts.user, &opts.pass);
-    let session_config = lsc::SessionConfig::default();
+    TEXT_TO_STDERR.store(opts.json, sync::atomic::Ordering::Relaxed);
 
-    let session = lsc::Session::new(session_config, None);
+    // Nothing about the library needs a login, it's only asked for once there's something to download
+    if let Some(root) = &opts.complete_albums {
+        opts.input = offer_album_completion(root);
 
-    match session.connect(credentials, false).await {
-        Ok(_) => {
-            println!("{} Logged in as: {}", "=>".green().bold(), &opts.user.bright_blue());
+        if opts.input.is_empty() {
+            proc::exit(0);
         }
+    }
+
+    // The budget covers the whole run, logging in and resolving inputs included
+    let deadline = opts.max_runtime.map(|max_runtime| time::Instant::now() + max_runtime);
+
+    let cache = match open_credentials_cache() {
+        Ok(cache) => Some(cache),
         Err(err) => {
-            println!(
+            say!(
+                "{}: cannot open credentials cache, the login won't be remembered: {}",
+                "warning".yellow().bold(),
+                err
+            );
+            None
+        }
+    };
+
+    let credentials = match &opts.credentials_file {
+        Some(path) => match read_credentials_file(path) {
+            Ok(credentials) => credentials,
+            Err(err) => {
+                say!(
+                    "{}: cannot read credentials file {}: {}",
+                    "error".red().bold(),
+                    path,
+                    err
+                );
+                proc::exit(1);
+            }
+        },
+        None if opts.token.is_some() => lsc_auth::Credentials::with_access_token(opts.token.as_deref().unwrap()),
+        None if opts.oauth => match auth::oauth_login(&session_config(&opts).client_id).await {
+            Ok(token) => lsc_auth::Credentials::with_access_token(token),
+            Err(err) => {
+                say!("{}: cannot log in with oauth: {}", "error".red().bold(), err);
+                proc::exit(1);
+            }
+        },
+        None if !opts.pass.is_empty() => lsc_auth::Credentials::with_password(&opts.user, &opts.pass),
+        None => match cache
+            .as_ref()
+            .and_then(|cache| cache.credentials())
+            .filter(|credentials| opts.user.is_empty() || credentials.username == opts.user)
+        {
+            Some(credentials) => credentials,
+            None => {
+                say!(
+                    "{}: no cached credentials{}, log in with -u and -p once",
+                    "error".red().bold(),
+                    if opts.user.is_empty() {
+                        "".to_owned()
+                    } else {
+                        format!(" for {}", opts.user)
+                    }
+                );
+                proc::exit(1);
+            }
+        },
+    };
+
+    let mut session = match connect_session(credentials.clone(), cache.clone(), session_config(&opts)).await {
+        Ok(session) => {
+            say!(
+                "{} Logged in as: {}",
+                "=>".green().bold(),
+                session.username().bright_blue()
+            );
+            session
+        }
+        Err(err) => {
+            say!(
                 "{}: cannot log in: {}",
                 "error".red().bold(),
                 err.to_string().to_lowercase()
@@ -47,671 +159,3863 @@ async fn main() {
         }
     };
 
-    println!("\n{} Input resources:", "=>".green().bold());
+    // Upgrades are always after the best quality, whatever --quality says
+    if let Some(root) = &opts.upgrade {
+        let downloader = TrackDownloader::new(session.clone(), opts.track_timeout, AudioQuality::High, false)
+            .with_rate_limit(opts.rate_limit);
+        upgrade_library(&session, &downloader, &opts, root).await;
+        return;
+    }
 
-    let input_resources: Vec<_> = opts
-        .input
-        .iter()
-        .map(|line| get_resource_from_line(line))
-        .filter(|x| {
-            if let Err(line) = x {
-                println!(
-                    "{}: unrecognized input: {}, skipping...",
-                    " -> warning".yellow().bold(),
-                    line.bold()
-                );
-                false
-            } else {
-                let res = x.as_ref().unwrap();
-                println!(
-                    " {} {}: {}",
-                    "->".yellow().bold(),
-                    res.kind,
-                    &res.id.to_base62().unwrap()
+    if let Some(count) = opts.bench {
+        let downloader = TrackDownloader::new(session.clone(), opts.track_timeout, opts.quality, opts.strict_quality);
+        proc::exit(run_bench(&session, &downloader, &opts, count).await);
+    }
+
+    let mut rejects = match RejectsFile::create(opts.rejects.as_deref()) {
+        Ok(rejects) => rejects,
+        Err(err) => {
+            say!(
+                "{}: cannot create rejects file {}: {}",
+                "error".red().bold(),
+                opts.rejects.as_deref().unwrap_or_default(),
+                err
+            );
+            proc::exit(1);
+        }
+    };
+
+    let blocklist = match opts.blocklist.as_deref().map(blocklist::Blocklist::load).transpose() {
+        Ok(blocklist) => blocklist.unwrap_or_default(),
+        Err(err) => {
+            say!(
+                "{}: cannot read blocklist {}: {}",
+                "error".red().bold(),
+                opts.blocklist.as_deref().unwrap_or_default(),
+                err
+            );
+            proc::exit(1);
+        }
+    };
+
+    let download_archive = match opts
+        .download_archive
+        .as_deref()
+        .map(archive::DownloadArchive::open)
+        .transpose()
+    {
+        Ok(download_archive) => download_archive,
+        Err(err) => {
+            say!(
+                "{}: cannot open download archive {}: {}",
+                "error".red().bold(),
+                opts.download_archive.as_deref().unwrap_or_default(),
+                err
+            );
+            proc::exit(1);
+        }
+    };
+
+    let mut owned_index = owned::OwnedIndex::default();
+
+    for dir in &opts.also_have {
+        match owned_index.scan(path::Path::new(dir)) {
+            Ok(count) => say!(
+                "{} Found {} tracks already owned in \"{}\"",
+                "=>".green().bold(),
+                count,
+                dir
+            ),
+            Err(err) => {
+                say!("{}: cannot read {}: {}", "error".red().bold(), dir, err);
+                proc::exit(1);
+            }
+        }
+    }
+
+    let mut batch = match opts.session.as_deref().map(batch::Batch::open).transpose() {
+        Ok(batch) => batch,
+        Err(err) => {
+            say!("{}: cannot open session: {}", "error".red().bold(), err);
+            proc::exit(1);
+        }
+    };
+
+    let metadata_cache = metadata::MetadataCache::default();
+
+    if let Some(query) = &opts.search {
+        opts.input = pick_search_results(&session, &metadata_cache, query).await;
+
+        if opts.input.is_empty() {
+            proc::exit(0);
+        }
+    }
+
+    let input_groups = match &batch {
+        Some(batch) if batch.is_resolved() => {
+            if !opts.input.is_empty() || opts.csv_match.is_some() {
+                say!(
+                    "{}: session {} was already resolved, ignoring new inputs",
+                    "note".bright_blue().bold(),
+                    batch.name.bold()
                 );
-                true
             }
-        })
-        .map(|x| x.unwrap())
-        .collect();
 
-    let mut input_tracks = coll::HashSet::<lsc::SpotifyId>::new();
+            say!(
+                "\n{} Resuming session {}: {} of {} tracks already processed",
+                "=>".green().bold(),
+                batch.name.bold(),
+                batch.state.queue.iter().filter(|id| batch.is_finished(id)).count(),
+                batch.state.queue.len()
+            );
+
+            vec![TrackGroup {
+                title: None,
+                name: None,
+                tracks: batch
+                    .state
+                    .queue
+                    .iter()
+                    .filter(|id| !batch.is_finished(id))
+                    .filter_map(|id| lsc::SpotifyId::from_base62(id).ok())
+                    .collect(),
+                radio: None,
+            }]
+        }
+        _ => resolve_input_tracks(&session, &opts, &mut rejects, &metadata_cache).await,
+    };
 
-    for res in &input_resources {
-        match res.get_tracks(&session).await {
-            Ok(tracks) => input_tracks.extend(tracks),
+    let num_tracks: usize = input_groups.iter().map(|group| group.tracks.len()).sum();
+
+    // Resolving big inputs can take long enough for the connection to drop. Rather than failing every download, log
+    // in again, with the reusable credentials the first login left in the cache when there are some
+    if session.is_invalid() {
+        let credentials = cache
+            .as_ref()
+            .and_then(|cache| cache.credentials())
+            .unwrap_or(credentials);
+
+        session = match connect_session(credentials, cache, session_config(&opts)).await {
+            Ok(session) => {
+                say!("\n{} Connection was lost, logged in again", "=>".green().bold());
+                session
+            }
             Err(err) => {
-                println!(
-                    "{}: cannot get metadata for {} {}: {}, skipping...",
-                    "warning".yellow().bold(),
-                    res.kind,
-                    res.id.to_base62().unwrap(),
-                    err
+                say!(
+                    "{}: cannot log in again: {}",
+                    "error".red().bold(),
+                    err.to_string().to_lowercase()
                 );
+                proc::exit(1);
+            }
+        };
+    }
+
+    let downloader = TrackDownloader::new(session.clone(), opts.track_timeout, opts.quality, opts.strict_quality)
+        .with_rate_limit(opts.rate_limit);
+
+    if let Some(batch) = &mut batch {
+        if !batch.is_resolved() {
+            let queue = input_groups
+                .iter()
+                .flat_map(|group| group.tracks.iter())
+                .map(|id| id.to_base62().unwrap())
+                .collect();
+
+            if let Err(err) = batch.set_queue(&opts.input, queue) {
+                say!("{}: cannot save session {}: {}", "error".red().bold(), batch.name, err);
+                proc::exit(1);
             }
+        } else if num_tracks == 0 {
+            say!(
+                "\n{} Session {} is already complete",
+                "=>".green().bold(),
+                batch.name.bold()
+            );
+            proc::exit(0);
         }
     }
 
-    if input_tracks.is_empty() {
-        println!("\n{}: didn't get any tracks, aborting...", "error".red().bold());
-        proc::exit(0);
+    if num_tracks == 0 {
+        say!("\n{}: didn't get any tracks, aborting...", "error".red().bold());
+        proc::exit(3);
     }
 
-    println!(
+    say!(
         "\n{} Parsed {} tracks:",
         "=>".green().bold(),
-        input_tracks.len().to_string().bold()
+        num_tracks.to_string().bold()
     );
 
+    if opts.json {
+        for group in &input_groups {
+            for track_id in &group.tracks {
+                print_event(serde_json::json!({
+                    "event": "parsed",
+                    "id": track_id.to_base62().unwrap(),
+                    "group": group.title,
+                }));
+            }
+        }
+    }
+
+    let mut num_processed: usize = 0;
     let mut num_completed: usize = 0;
     let mut num_existing: usize = 0;
+    let mut num_blocked: usize = 0;
+    let track_pipe = opts.pipe.as_deref().map(pipe::TrackPipe::open);
 
-    for track_id in &input_tracks {
-        print!(" {} ", "->".yellow().bold());
-
-        let (track, file_id) = match get_track_from_id(&session, track_id).await {
-            Ok((track, file_id)) => {
-                if track.id.to_base62().unwrap() != track_id.to_base62().unwrap() {
-                    println!(
-                        "{} ({} alt. {})",
-                        track.name.bold(),
-                        track.id.to_base62().unwrap(),
-                        track_id.to_base62().unwrap()
-                    );
-                } else {
-                    println!("{} ({})", track.name.bold(), track.id.to_base62().unwrap());
-                }
+    // Checksums of the audio already in the library, to catch the same recording published under another id
+    let audio_index = sync::Mutex::new(if opts.format.has_album_dirs() {
+        manifest::AlbumManifest::audio_index(path::Path::new(&opts.format.root()))
+    } else {
+        coll::HashMap::new()
+    });
+
+    let recordings = sync::Mutex::new(coll::HashMap::new());
+    let output_files = sync::Mutex::new(coll::HashMap::new());
+    let cover_dirs = sync::Mutex::new(coll::HashSet::new());
+
+    // Bars are drawn on stderr below the printed lines, and only when it's a terminal
+    let progress = indicatif::MultiProgress::new();
+    let total_progress = progress.add(
+        indicatif::ProgressBar::new(num_tracks as u64).with_style(
+            indicatif::ProgressStyle::with_template("{pos}/{len} tracks [{bar:40}] {elapsed}")
+                .unwrap()
+                .progress_chars("=> "),
+        ),
+    );
+
+    let context = TrackContext {
+        session: &session,
+        opts: &opts,
+        downloader: &downloader,
+        metadata_cache: &metadata_cache,
+        audio_index: &audio_index,
+        blocklist: &blocklist,
+        download_archive: download_archive.as_ref(),
+        owned_index: &owned_index,
+        recordings: &recordings,
+        output_files: &output_files,
+        cover_dirs: &cover_dirs,
+        keep_buffer: track_pipe.is_some(),
+        progress: &progress,
+    };
 
-                (track, file_id)
+    // The first Ctrl-C or SIGTERM stops new tracks from being started, and the run winds down as with --max-runtime. A
+    // second one exits right away, which leaves nothing worse than .part files behind
+    let interrupted = sync::Arc::new(sync::atomic::AtomicBool::new(false));
+    tokio::spawn(watch_interrupts(interrupted.clone()));
+
+    // Up to --jobs tracks are processed at once. Each one reports back when done and its lines are printed together,
+    // under the title of its group when that isn't the one printed last
+    // Past --max-runtime no more tracks are started, the ones already going are still finished
+    // With --sleep-between, the first tracks start right away and every one after them waits its turn
+    let mut outcomes = stream::iter(input_groups.iter().flat_map(|group| {
+        group
+            .tracks
+            .iter()
+            .map(move |track_id| (group.title.as_deref(), group.radio.as_ref(), track_id))
+    }))
+    .take_while(|_| {
+        future::ready(
+            deadline.is_none_or(|deadline| time::Instant::now() < deadline)
+                && !interrupted.load(sync::atomic::Ordering::Relaxed),
+        )
+    })
+    .enumerate()
+    .map(|(index, (title, radio, track_id))| {
+        let context = &context;
+        async move {
+            if let Some((min, max)) = opts.sleep_between.filter(|_| index >= opts.jobs) {
+                tokio::time::sleep(min + (max - min).mul_f64(rand::random::<f64>())).await;
             }
-            Err(err) => {
-                println!("{} ({})", "??".bold(), track_id.to_base62().unwrap());
-                println!(
-                    "   - {}: cannot get track from id: {}, skipping...",
-                    "warning".yellow().bold(),
-                    err,
-                );
-                continue;
+
+            (
+                title,
+                radio,
+                track_id,
+                process_track(context, track_id, title.is_some()).await,
+            )
+        }
+    })
+    .buffer_unordered(opts.jobs);
+
+    let mut last_title: Option<Option<&str>> = None;
+    let mut synced_files = Vec::<(String, String)>::new();
+    let mut playlist_entries = coll::HashMap::<String, manifest::PlaylistEntry>::new();
+
+    while let Some((title, radio, track_id, outcome)) = outcomes.next().await {
+        // Bars are cleared while printing, or they'd end up mixed in with the lines
+        progress.suspend(|| {
+            if last_title != Some(title) && !opts.json {
+                if let Some(title) = title {
+                    say!(" {} {}", "->".yellow().bold(), title.bold());
+                }
+                last_title = Some(title);
             }
-        };
 
-        let output_file = opts.format.parse_output_format(&track);
+            if opts.json {
+                print_track_event(track_id, title, radio, &outcome);
+            } else {
+                for line in &outcome.lines {
+                    say!("{}", line);
+                }
+            }
 
-        if path::Path::new(&output_file.file).exists() {
-            println!(
-                "   - {}: output file \"{}\" already exists, skipping...",
-                "note".bright_blue().bold(),
-                output_file.file
-            );
+            if let Some(reason) = &outcome.reject {
+                rejects.add(&format!("spotify:track:{}", track_id.to_base62().unwrap()), reason);
+            }
 
-            num_existing += 1;
-            continue;
-        }
+            if let Some(written) = outcome.written {
+                // Album manifests are only touched from here, so tracks of the same album never update one at the same time
+                if let Some((dir, album, mut manifest_track)) = written.manifest {
+                    manifest_track.radio = radio.cloned();
 
-        let buffer = match track_download(&track, &file_id, &session).await {
-            Ok(buffer) => buffer,
-            Err(err) => {
-                match err.kind {
-                    TrackDownloadErrorKind::AudioKey => {
-                        println!(
-                            "   - {}: cannot get audio key: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                    TrackDownloadErrorKind::AudioFile => {
-                        println!(
-                            "   - {}: cannot get audio file: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                    TrackDownloadErrorKind::TrackFile => {
-                        println!(
-                            "   - {}: cannot get track file audio: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                    TrackDownloadErrorKind::Decrypt => {
-                        println!(
-                            "   - {}: cannot decrypt audio file: {}, skipping...",
+                    if let Err(err) = manifest::AlbumManifest::record(path::Path::new(&dir), album, manifest_track) {
+                        say!(
+                            "{}   - {}: cannot update album manifest: {}",
+                            if title.is_some() { "   " } else { "" },
                             "warning".yellow().bold(),
-                            err.error
+                            err
                         );
                     }
-                };
-                continue;
+                }
+
+                if let (Some(track_pipe), Some(buffer)) = (&track_pipe, written.buffer) {
+                    track_pipe.send(buffer);
+                }
             }
-        };
 
-        let buffer_tags = match track_add_metadata_tags(buffer, &track) {
-            Ok(buf) => buf,
-            Err(err) => {
-                match err.kind {
-                    TagsWriteErrorKind::Read => {
-                        print!(
-                            "   - {}: cannot read ogg packet: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                    TagsWriteErrorKind::Write => {
-                        print!(
-                            "   - {}: cannot write ogg packet: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                    TagsWriteErrorKind::Header => {
-                        print!(
-                            "   - {}: cannot create comment header packet: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
+            // Alternatives go in under both ids, so the track is known whichever one it's asked for by next time
+            if let (Some(download_archive), batch::TrackResult::Done) = (&download_archive, &outcome.result) {
+                let added =
+                    download_archive
+                        .add(track_id.to_base62().unwrap())
+                        .and_then(|_| match &outcome.alternative {
+                            Some(alternative) => download_archive.add(alternative.clone()),
+                            None => Ok(()),
+                        });
+
+                if let Err(err) = added {
+                    say!(
+                        "{}   - {}: cannot update download archive: {}",
+                        if title.is_some() { "   " } else { "" },
+                        "warning".yellow().bold(),
+                        err
+                    );
                 }
+            }
+
+            if let (Some(_), Some(file)) = (&opts.sync, outcome.file) {
+                synced_files.push((track_id.to_base62().unwrap(), file));
+            }
+
+            if let (true, Some(entry)) = (opts.write_playlist, outcome.entry) {
+                playlist_entries.insert(track_id.to_base62().unwrap(), entry);
+            }
+
+            num_processed += 1;
+
+            match outcome.result {
+                batch::TrackResult::Done => num_completed += 1,
+                batch::TrackResult::Existing => num_existing += 1,
+                batch::TrackResult::Blocked => num_blocked += 1,
+                batch::TrackResult::Failed(_) => {}
+            }
+
+            record_batch_result(&mut batch, track_id, outcome.result);
+        });
+
+        total_progress.inc(1);
+    }
+
+    drop(outcomes);
+    total_progress.finish_and_clear();
+
+    let num_failed = num_processed - num_completed - num_existing - num_blocked;
+
+    say!("\n{} Processed tracks: ", "=>".green().bold(),);
+
+    say!(" {} {} error", "->".yellow().bold(), num_failed);
+
+    say!(" {} {} already downloaded", "->".yellow().bold(), num_existing);
+
+    if num_blocked > 0 {
+        say!(" {} {} blocked", "->".yellow().bold(), num_blocked);
+    }
+
+    let radios = input_groups
+        .iter()
+        .filter_map(|group| group.radio.as_ref().map(|radio| (radio, group.tracks.len())))
+        .collect::<Vec<_>>();
+
+    for (radio, num_received) in &radios {
+        say!(
+            " {} {} from the radio seeded from {} ({} requested)",
+            "->".yellow().bold(),
+            num_received,
+            radio.seed,
+            radio.count
+        );
+    }
+
+    say!(" {} {} new", "->".yellow().bold(), num_completed);
+
+    say!(" {} {} total processed", "->".yellow().bold(), num_processed);
+
+    let interrupted = interrupted.load(sync::atomic::Ordering::Relaxed);
+
+    if num_processed < num_tracks {
+        say!(
+            " {} {} not started, {}",
+            "->".yellow().bold(),
+            num_tracks - num_processed,
+            if interrupted {
+                "the run was interrupted"
+            } else {
+                "--max-runtime was reached"
+            }
+        );
+    }
+
+    if opts.json {
+        print_event(serde_json::json!({
+            "event": "summary",
+            "failed": num_failed,
+            "existing": num_existing,
+            "blocked": num_blocked,
+            "downloaded": num_completed,
+            "not_started": num_tracks - num_processed,
+            "total": num_tracks,
+            "radio": radios
+                .iter()
+                .map(|(radio, num_received)| serde_json::json!({
+                    "seed": radio.seed,
+                    "count": radio.count,
+                    "received": num_received,
+                }))
+                .collect::<Vec<_>>(),
+        }));
+    }
+
+    if opts.write_playlist {
+        write_group_playlists(&opts.format, opts.playlist_paths, &input_groups, &playlist_entries);
+    }
+
+    if let Err(err) = opts.format.artist_names.save() {
+        say!(
+            "\n{}: cannot save canonical artist names: {}",
+            "warning".yellow().bold(),
+            err
+        );
+    }
+
+    if let Some(sync) = &opts.sync {
+        // A playlist that couldn't be read entirely would make every missing track look removed
+        let complete = rejects.count == 0;
+        finish_sync(sync, &opts.input[0], &input_groups, synced_files, complete);
+    }
+
+    if rejects.count > 0 && rejects.file.is_some() {
+        say!(
+            "\n{} {} unresolved inputs added to \"{}\"",
+            "=>".green().bold(),
+            rejects.count,
+            rejects.path
+        );
+    }
+
+    if let Some(track_pipe) = track_pipe {
+        if track_pipe.pending() > 0 {
+            say!(
+                "\n{} Waiting for {} tracks to be read from \"{}\"...",
+                "=>".green().bold(),
+                track_pipe.pending(),
+                opts.pipe.as_deref().unwrap_or_default()
+            );
+        }
+
+        track_pipe.finish();
+    }
+
+    if num_processed < num_tracks {
+        say!(
+            "\n{} Stopped early, {}",
+            "=>".green().bold(),
+            match &opts.session {
+                Some(session) => format!("run again with --session {} to continue", session),
+                None => "run again to continue, tracks already downloaded will be skipped".to_owned(),
+            }
+        );
+    }
+
+    // Like a shell reports a process killed by SIGINT, so scripts can tell an interrupted run from a finished one
+    if interrupted {
+        proc::exit(130);
+    }
+
+    proc::exit(run_exit_code(num_processed, num_failed));
+}
+
+// 0 when every track was downloaded or was there already, 2 when some failed and 3 when none made it, or there were
+// none to begin with. Errors that stop the run before any track is tried exit with 1
+fn run_exit_code(num_processed: usize, num_failed: usize) -> i32 {
+    if num_failed == 0 {
+        0
+    } else if num_failed < num_processed {
+        2
+    } else {
+        3
+    }
+}
+
+async fn watch_interrupts(interrupted: sync::Arc<sync::atomic::AtomicBool>) {
+    loop {
+        interrupt_signal().await;
+
+        if interrupted.swap(true, sync::atomic::Ordering::Relaxed) {
+            proc::exit(130);
+        }
+
+        say!(
+            "\n{}: interrupted, finishing the tracks in progress. Interrupt again to stop right away",
+            "note".bright_blue().bold()
+        );
+    }
+}
+
+#[cfg(unix)]
+async fn interrupt_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    match signal(SignalKind::terminate()) {
+        Ok(mut terminate) => {
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        }
+        Err(_) => {
+            let _ = tokio::signal::ctrl_c().await;
+        }
+    }
+}
+
+#[cfg(not(unix))]
+async fn interrupt_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+struct TrackContext<'a> {
+    session: &'a lsc::Session,
+    opts: &'a UserParams,
+    downloader: &'a TrackDownloader,
+    metadata_cache: &'a metadata::MetadataCache,
+    audio_index: &'a sync::Mutex<coll::HashMap<String, path::PathBuf>>,
+    blocklist: &'a blocklist::Blocklist,
+    download_archive: Option<&'a archive::DownloadArchive>,
+    owned_index: &'a owned::OwnedIndex,
+    recordings: &'a sync::Mutex<coll::HashMap<String, String>>,
+    output_files: &'a sync::Mutex<coll::HashMap<String, String>>,
+    cover_dirs: &'a sync::Mutex<coll::HashSet<String>>,
+    keep_buffer: bool,
+    progress: &'a indicatif::MultiProgress,
+}
+
+// What became of a track, with the lines to print for it
+struct TrackOutcome {
+    lines: Vec<String>,
+    result: batch::TrackResult,
+    reject: Option<String>,
+    written: Option<WrittenTrack>,
+    file: Option<String>,
+    entry: Option<manifest::PlaylistEntry>,
+    audio: Option<manifest::AudioInfo>,
+    // Id of the alternative that was downloaded in place of the track, if it was
+    alternative: Option<String>,
+}
+
+struct WrittenTrack {
+    manifest: Option<(String, manifest::AlbumManifest, manifest::ManifestTrack)>,
+    buffer: Option<Vec<u8>>,
+}
+
+impl TrackOutcome {
+    fn new(lines: Vec<String>, result: batch::TrackResult) -> TrackOutcome {
+        TrackOutcome {
+            lines,
+            result,
+            reject: None,
+            written: None,
+            file: None,
+            entry: None,
+            audio: None,
+            alternative: None,
+        }
+    }
+}
+
+async fn process_track(context: &TrackContext<'_>, track_id: &lsc::SpotifyId, grouped: bool) -> TrackOutcome {
+    let opts = context.opts;
+    let indent = if grouped { "   " } else { "" };
+    let mut lines = Vec::<String>::new();
+
+    let (track, file_id) = match context.downloader.get_track(track_id, context.metadata_cache).await {
+        Ok((track, file_id)) => {
+            let quality = track_file_audio_quality(&track, &file_id);
+            let bitrate = quality.map_or("??".to_owned(), |quality| quality.kbps().to_string());
+
+            if track.id.to_base62().unwrap() != track_id.to_base62().unwrap() {
+                lines.push(format!(
+                    "{} {} {} ({} alt. {}, {} kbps)",
+                    indent,
+                    "->".yellow().bold(),
+                    track.name.bold(),
+                    track.id.to_base62().unwrap(),
+                    track_id.to_base62().unwrap(),
+                    bitrate
+                ));
+            } else {
+                lines.push(format!(
+                    "{} {} {} ({}, {} kbps)",
+                    indent,
+                    "->".yellow().bold(),
+                    track.name.bold(),
+                    track.id.to_base62().unwrap(),
+                    bitrate
+                ));
+            }
+
+            if quality.is_some_and(|quality| quality < opts.quality) {
+                lines.push(format!(
+                    "{}   - {}: not available at {} kbps, downloading at {} kbps instead",
+                    indent,
+                    "warning".yellow().bold(),
+                    opts.quality.kbps(),
+                    bitrate
+                ));
+            }
+
+            (track, file_id)
+        }
+        Err(err) => {
+            lines.push(format!(
+                "{} {} {} ({})",
+                indent,
+                "->".yellow().bold(),
+                "??".bold(),
+                track_id.to_base62().unwrap()
+            ));
+            lines.push(format!(
+                "{}   - {}: cannot get track from id: {}, skipping...",
+                indent,
+                "warning".yellow().bold(),
+                err,
+            ));
+
+            let reason = format!("cannot get track from id: {}", err);
+            return TrackOutcome {
+                reject: Some(reason.clone()),
+                ..TrackOutcome::new(lines, batch::TrackResult::Failed(reason))
+            };
+        }
+    };
+
+    let mut outcome = write_track(context, track_id, &track, &file_id, indent, lines).await;
+    outcome.alternative = Some(track.id.to_base62().unwrap()).filter(|id| *id != track_id.to_base62().unwrap());
+
+    // Wherever the track ended up, even when it was already there, so --write-playlist can list it
+    outcome.entry = outcome.file.as_ref().map(|file| manifest::PlaylistEntry {
+        file: path::PathBuf::from(file),
+        title: match track.artists.first() {
+            Some(artist) => format!("{} - {}", artist.name, track.name),
+            None => track.name.clone(),
+        },
+        duration_ms: track.duration,
+    });
+
+    outcome
+}
+
+async fn write_track(
+    context: &TrackContext<'_>,
+    track_id: &lsc::SpotifyId,
+    track: &lsm::Track,
+    file_id: &lsc::FileId,
+    indent: &str,
+    mut lines: Vec<String>,
+) -> TrackOutcome {
+    let opts = context.opts;
+
+    if context.blocklist.contains_id(track_id) || context.blocklist.contains_track(track) {
+        lines.push(format!(
+            "{}   - {}: track is in the blocklist, skipping...",
+            indent,
+            "note".bright_blue().bold()
+        ));
+
+        return TrackOutcome::new(lines, batch::TrackResult::Blocked);
+    }
+
+    if context.download_archive.is_some_and(|download_archive| {
+        download_archive.contains(&track_id.to_base62().unwrap())
+            || download_archive.contains(&track.id.to_base62().unwrap())
+    }) {
+        lines.push(format!(
+            "{}   - {}: track is in the download archive, skipping...",
+            indent,
+            "note".bright_blue().bold()
+        ));
+
+        return TrackOutcome::new(lines, batch::TrackResult::Existing);
+    }
+
+    if let Some(owned_file) = context.owned_index.find(track) {
+        lines.push(format!(
+            "{}   - {}: already owned as \"{}\", skipping...",
+            indent,
+            "note".bright_blue().bold(),
+            owned_file.display()
+        ));
+
+        return TrackOutcome::new(lines, batch::TrackResult::Existing);
+    }
+
+    // The first track of a recording claims it, even before it's downloaded, so copies being processed at the same
+    // time don't both go through
+    if let Some(key) = recording_key(opts.dedup, track) {
+        let first = context
+            .recordings
+            .lock()
+            .unwrap()
+            .entry(key)
+            .or_insert_with(|| track_id.to_base62().unwrap())
+            .clone();
+
+        if first != track_id.to_base62().unwrap() {
+            lines.push(format!(
+                "{}   - {}: same recording as track {}, skipping...",
+                indent,
+                "note".bright_blue().bold(),
+                first
+            ));
+
+            return TrackOutcome::new(lines, batch::TrackResult::Existing);
+        }
+    }
+
+    let album_info = context.metadata_cache.album_info(context.session, track).await;
+
+    let requested_id = Some(track_id.to_base62().unwrap()).filter(|id| *id != track.id.to_base62().unwrap());
+
+    // Previews are mp3 clips, so they never get mixed up with full tracks in the same folder
+    let expected_container = if opts.preview {
+        AudioContainer::Mp3
+    } else {
+        AudioContainer::Ogg
+    };
+
+    // Previews come at a bitrate of their own, which isn't the one of the track's file
+    let bitrate = track_file_audio_quality(track, file_id)
+        .filter(|_| !opts.preview)
+        .map(|quality| quality.kbps());
+
+    let output_extension = |container: AudioContainer| {
+        opts.encode
+            .map_or(container.extension(), |encoding| encoding.extension())
+    };
+
+    let output_file = opts.format.parse_output_format(
+        track,
+        album_info.disc_count,
+        bitrate,
+        output_extension(expected_container),
+    );
+    let mut output_file = claim_output_file(context, track_id, output_file, indent, &mut lines);
+
+    if skip_existing(opts, &output_file.file, indent, &mut lines) {
+        return TrackOutcome {
+            file: Some(output_file.file),
+            ..TrackOutcome::new(lines, batch::TrackResult::Existing)
+        };
+    }
+
+    // Tracks are still written when their lyrics can't be fetched
+    let lyrics = if opts.lyrics {
+        match TrackLyrics::get(context.session, track).await {
+            Ok(lyrics) => lyrics,
+            Err(err) => {
+                lines.push(format!(
+                    "{}   - {}: cannot get lyrics: {}",
+                    indent,
+                    "note".bright_blue().bold(),
+                    err
+                ));
+                None
+            }
+        }
+    } else {
+        None
+    };
+
+    let track_progress = context.progress.add(
+        indicatif::ProgressBar::new(0)
+            .with_style(
+                indicatif::ProgressStyle::with_template("{wide_msg} {bytes:>10}/{total_bytes:<10} [{bar:30}]")
+                    .unwrap()
+                    .progress_chars("=> "),
+            )
+            .with_message(track.name.clone()),
+    );
+
+    // Full tracks are tagged and written to disk as they come in. Previews, transcodes and tracks that also go down
+    // the pipe need the whole file in memory anyway
+    let streamed = !opts.preview && opts.encode.is_none() && !context.keep_buffer;
+    let mut part_file = track_part_file(&output_file, 0);
+
+    let comment_header = track_comment_header(
+        track,
+        &album_info,
+        &opts.tag_featuring,
+        lyrics.as_ref(),
+        requested_id.as_deref(),
+    );
+
+    // Network errors, and bad keys or corrupted transfers (which can't be told apart), are retried up to --retries
+    // times. Tracks the server refuses fail right away
+    let mut attempt: u32 = 0;
+
+    let download = loop {
+        let download = if opts.preview {
+            let download = context.downloader.download_preview(track).await;
+            download.map(|(container, buffer)| Ok(Download::Buffered(container, buffer)))
+        } else if streamed {
+            let output_file = output_file.clone();
+            let audio_info_tags = opts.audio_info_tags;
+            let comment_header = comment_header.clone();
+
+            context
+                .downloader
+                .download_with(track, file_id, Some(&track_progress), move |_, audio| {
+                    stream_track(
+                        audio,
+                        &output_file,
+                        attempt,
+                        &comment_header,
+                        audio_info_tags,
+                    )
+                    .map(Download::Streamed)
+                })
+                .await
+        } else {
+            let download = context.downloader.download(track, file_id, Some(&track_progress)).await;
+            download.map(|(container, buffer)| Ok(Download::Buffered(container, buffer)))
+        };
+
+        match download {
+            Err(err) if err.kind.is_retryable() && (attempt as usize) < opts.retries => {
+                let delay = retry_delay(attempt);
+
+                lines.push(format!(
+                    "{}   - {}: {}: {}, retrying in {:.1}s...",
+                    indent,
+                    "note".bright_blue().bold(),
+                    download_problem(&err.kind),
+                    err.error,
+                    delay.as_secs_f64()
+                ));
+
+                let _ = fs::remove_file(&part_file);
+
+                attempt += 1;
+                part_file = track_part_file(&output_file, attempt);
+                track_progress.reset();
+                tokio::time::sleep(delay).await;
+            }
+            download => break download,
+        }
+    };
+
+    track_progress.finish_and_clear();
+    context.progress.remove(&track_progress);
+
+    let download = match download {
+        Ok(Ok(download)) => download,
+        Ok(Err((problem, error))) => {
+            let _ = fs::remove_file(&part_file);
+
+            lines.push(format!(
+                "{}   - {}: {}: {}, skipping...",
+                indent,
+                "warning".yellow().bold(),
+                problem,
+                error
+            ));
+            return TrackOutcome::new(lines, batch::TrackResult::Failed(error.to_string()));
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&part_file);
+
+            lines.push(format!(
+                "{}   - {}: {}: {}, skipping...",
+                indent,
+                "warning".yellow().bold(),
+                download_problem(&err.kind),
+                err.error
+            ));
+            return TrackOutcome::new(lines, batch::TrackResult::Failed(err.error.to_string()));
+        }
+    };
+
+    let container = match &download {
+        Download::Streamed(_) => AudioContainer::Ogg,
+        Download::Buffered(container, _) => *container,
+    };
+
+    // Full tracks are always ogg vorbis, only a preview can come in another container than expected
+    if container != expected_container {
+        output_file =
+            opts.format
+                .parse_output_format(track, album_info.disc_count, bitrate, output_extension(container));
+        output_file = claim_output_file(context, track_id, output_file, indent, &mut lines);
+
+        if skip_existing(opts, &output_file.file, indent, &mut lines) {
+            let _ = fs::remove_file(&part_file);
+
+            return TrackOutcome {
+                file: Some(output_file.file),
+                ..TrackOutcome::new(lines, batch::TrackResult::Existing)
+            };
+        }
+    }
+
+    let audio_sha256 = match &download {
+        Download::Streamed(streamed) => streamed.audio_sha256.clone(),
+        Download::Buffered(_, buffer) => manifest::sha256(buffer),
+    };
+
+    if let Some(existing) = context.audio_index.lock().unwrap().get(&audio_sha256) {
+        let _ = fs::remove_file(&part_file);
+
+        lines.push(format!(
+            "{}   - {}: same audio as \"{}\", skipping...",
+            indent,
+            "note".bright_blue().bold(),
+            existing.display()
+        ));
+
+        return TrackOutcome {
+            file: Some(existing.to_string_lossy().into_owned()),
+            ..TrackOutcome::new(lines, batch::TrackResult::Existing)
+        };
+    }
+
+    let (sha256, size, audio, buffer) = match download {
+        Download::Streamed(streamed) => (streamed.sha256, streamed.size, streamed.audio, None),
+        Download::Buffered(container, buffer) => {
+            let tagged = track_add_metadata_tags(buffer, &comment_header, container, opts.audio_info_tags);
+
+            let (buffer_tags, audio) = match tagged {
+                Ok(tagged) => tagged,
+                Err(err) => {
+                    lines.push(format!(
+                        "{}   - {}: {}: {}, skipping...",
+                        indent,
+                        "warning".yellow().bold(),
+                        tags_problem(&err.kind),
+                        err.error
+                    ));
+                    return TrackOutcome::new(lines, batch::TrackResult::Failed(err.error.to_string()));
+                }
+            };
+
+            let buffer_tags = match opts.encode {
+                Some(encoding) => match transcode::transcode(buffer_tags, encoding).await {
+                    Ok(buf) => buf,
+                    Err(err) => {
+                        let problem = match err.kind {
+                            TranscodeErrorKind::Spawn => "cannot run ffmpeg",
+                            TranscodeErrorKind::Encode => "cannot transcode audio",
+                        };
+
+                        lines.push(format!(
+                            "{}   - {}: {}: {}, skipping...",
+                            indent,
+                            "warning".yellow().bold(),
+                            problem,
+                            err.error
+                        ));
+                        return TrackOutcome::new(lines, batch::TrackResult::Failed(err.error.to_string()));
+                    }
+                },
+                None => buffer_tags,
+            };
+
+            part_file = track_part_file(&output_file, 0);
+
+            let written = track_create_part(&output_file, 0).and_then(|mut file| {
+                file.write_all(&buffer_tags).map_err(|e| TrackWriteError {
+                    kind: TrackWriteErrorKind::FileWrite,
+                    error: e.into(),
+                })
+            });
+
+            if let Err(err) = written {
+                let _ = fs::remove_file(&part_file);
+
+                lines.push(format!(
+                    "{}   - {}: {}: {}, skipping...",
+                    indent,
+                    "warning".yellow().bold(),
+                    write_problem(&err.kind),
+                    err.error
+                ));
+                return TrackOutcome::new(lines, batch::TrackResult::Failed(err.error.to_string()));
+            }
+
+            (
+                manifest::sha256(&buffer_tags),
+                buffer_tags.len() as u64,
+                audio,
+                context.keep_buffer.then_some(buffer_tags),
+            )
+        }
+    };
+
+    // Previews are kept out of album manifests, they aren't the real thing
+    let manifest_track = (opts.format.has_album_dirs() && !opts.preview).then(|| manifest::ManifestTrack {
+        id: track.id.to_base62().unwrap_or_default(),
+        disc_number: track.disc_number,
+        number: track.number,
+        name: track.name.clone(),
+        duration_ms: track.duration,
+        file: path::Path::new(&output_file.file)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        quality: track_file_quality(track, file_id),
+        size,
+        sha256,
+        audio_sha256: audio_sha256.clone(),
+        audio,
+        requested_id: requested_id.clone(),
+        // Filled in with the track's group, which isn't known here
+        radio: None,
+    });
+
+    let output_dir = output_file.dir.clone().unwrap_or_default();
+
+    let mtime = opts.reproducible.then(|| reproducible_mtime(track));
+
+    match track_finish_part(&part_file, output_file, mtime) {
+        Ok(output) => {
+            lines.push(format!("{}   - wrote \"{}\"", indent, output));
+
+            if let Some(lyrics) = lyrics.filter(|lyrics| opts.lyrics_files && lyrics.synced) {
+                let lrc_file = path::Path::new(&output).with_extension("lrc");
+
+                if let Err(err) = fs::write(&lrc_file, format!("{}\n", lyrics.text)) {
+                    lines.push(format!(
+                        "{}   - {}: cannot write \"{}\": {}",
+                        indent,
+                        "warning".yellow().bold(),
+                        lrc_file.display(),
+                        err
+                    ));
+                }
+            }
+
+            // Only the first track of an album to be written in a run looks for its cover
+            if let Some(size) = opts.write_art.filter(|_| opts.format.has_album_dirs()) {
+                if context.cover_dirs.lock().unwrap().insert(output_dir.clone()) {
+                    let dir = path::Path::new(&output_dir);
+
+                    match cover::write_cover(context.session, &track.album, size, dir).await {
+                        Ok(true) => lines.push(format!("{}   - wrote \"{}\"", indent, dir.join("cover.jpg").display())),
+                        Ok(false) => {}
+                        Err(err) => lines.push(format!(
+                            "{}   - {}: cannot write album cover: {}",
+                            indent,
+                            "warning".yellow().bold(),
+                            err
+                        )),
+                    }
+                }
+            }
+
+            if manifest_track.is_some() {
+                context
+                    .audio_index
+                    .lock()
+                    .unwrap()
+                    .insert(audio_sha256, path::PathBuf::from(&output));
+            }
+
+            let manifest = manifest_track.map(|manifest_track| {
+                let album = manifest::AlbumManifest {
+                    album: track.album.name.clone(),
+                    album_id: track.album.id.to_base62().unwrap_or_default(),
+                    artist: track
+                        .album
+                        .artists
+                        .first()
+                        .map(|artist| artist.name.clone())
+                        .unwrap_or_default(),
+                    year: Some(track.album.date.year()).filter(|year| *year > 0),
+                    total_tracks: album_info.track_count,
+                    tracks: Vec::new(),
+                };
+
+                (output_dir, album, manifest_track)
+            });
+
+            TrackOutcome {
+                written: Some(WrittenTrack { manifest, buffer }),
+                file: Some(output),
+                audio,
+                ..TrackOutcome::new(lines, batch::TrackResult::Done)
+            }
+        }
+        Err(err) => {
+            let _ = fs::remove_file(&part_file);
+
+            lines.push(format!(
+                "{}   - {}: {}: {}, skipping...",
+                indent,
+                "warning".yellow().bold(),
+                write_problem(&err.kind),
+                err.error
+            ));
+            TrackOutcome::new(lines, batch::TrackResult::Failed(err.error.to_string()))
+        }
+    }
+}
+
+enum Download {
+    Streamed(StreamedTrack),
+    Buffered(AudioContainer, Vec<u8>),
+}
+
+struct StreamedTrack {
+    audio_sha256: String,
+    sha256: String,
+    size: u64,
+    audio: Option<manifest::AudioInfo>,
+}
+
+type StreamProblem = (&'static str, Box<dyn std::error::Error + Send + Sync>);
+
+// Runs on the download's own thread, with the audio read as it arrives, which is always ogg vorbis. Checksums are
+// taken on the way through, of the audio as it came and of the file as it was written
+fn stream_track(
+    audio: &mut dyn io::Read,
+    output_file: &OutputFile,
+    attempt: u32,
+    comment_header: &lhr::CommentHeader,
+    audio_info_tags: bool,
+) -> Result<StreamedTrack, StreamProblem> {
+    let file = track_create_part(output_file, attempt).map_err(|e| (write_problem(&e.kind), e.error))?;
+
+    let mut reader = manifest::HashingReader::new(audio);
+    let mut writer = manifest::HashingWriter::new(io::BufWriter::new(file));
+
+    let audio = replace_header_comment(&mut reader, &mut writer, comment_header, audio_info_tags)
+        .map_err(|e| (tags_problem(&e.kind), e.error))?;
+
+    // Whatever follows the last page still counts towards the audio checksum
+    io::copy(&mut reader, &mut io::sink()).map_err(|e| ("cannot read ogg packet", e.into()))?;
+
+    let (file, sha256, size) = writer.finish();
+    file.into_inner()
+        .map_err(|e| ("cannot write output file", e.into_error().into()))?;
+
+    Ok(StreamedTrack {
+        audio_sha256: reader.finish(),
+        sha256,
+        size,
+        audio,
+    })
+}
+
+fn print_event(event: serde_json::Value) {
+    println!("{}", event);
+}
+
+fn print_track_event(
+    track_id: &lsc::SpotifyId,
+    group: Option<&str>,
+    radio: Option<&manifest::RadioSeed>,
+    outcome: &TrackOutcome,
+) {
+    let (event, reason) = match &outcome.result {
+        batch::TrackResult::Done => ("downloaded", None),
+        batch::TrackResult::Existing => ("skipped", Some("existing")),
+        batch::TrackResult::Blocked => ("skipped", Some("blocked")),
+        batch::TrackResult::Failed(reason) => ("failed", Some(reason.as_str())),
+    };
+
+    print_event(serde_json::json!({
+        "event": event,
+        "id": track_id.to_base62().unwrap(),
+        "alternative": outcome.alternative,
+        "group": group,
+        "radio": radio,
+        "file": outcome.file,
+        "audio": outcome.audio,
+        "reason": reason,
+    }));
+}
+
+// How tracks are told to be the same. Ids only catch the same track listed twice, the same recording released on an
+// album, a single and a compilation has a different id on each but shares its isrc
+#[derive(Clone, Copy, PartialEq)]
+enum DedupMode {
+    Id,
+    Isrc,
+    None,
+}
+
+impl DedupMode {
+    fn from_name(mode: &str) -> Option<DedupMode> {
+        match mode {
+            "id" => Some(DedupMode::Id),
+            "isrc" => Some(DedupMode::Isrc),
+            "none" => Some(DedupMode::None),
+            _ => None,
+        }
+    }
+}
+
+// Tracks without an isrc fall back to their name, main artist and duration in seconds
+fn recording_key(dedup: DedupMode, track: &lsm::Track) -> Option<String> {
+    if dedup != DedupMode::Isrc {
+        return None;
+    }
+
+    let isrc = track
+        .external_ids
+        .iter()
+        .find(|external_id| external_id.external_type == "isrc")
+        .map(|external_id| format!("isrc:{}", external_id.id.to_uppercase()));
+
+    isrc.or_else(|| {
+        Some(format!(
+            "name:{}:{}:{}",
+            matching::normalize(&track.name),
+            matching::normalize(track.artists.first().map_or("", |artist| artist.name.as_str())),
+            (track.duration + 500) / 1000
+        ))
+    })
+}
+
+fn tags_problem(kind: &TagsWriteErrorKind) -> &'static str {
+    match kind {
+        TagsWriteErrorKind::Read => "cannot read ogg packet",
+        TagsWriteErrorKind::Write => "cannot write ogg packet",
+        TagsWriteErrorKind::Header => "cannot create comment header packet",
+    }
+}
+
+fn write_problem(kind: &TrackWriteErrorKind) -> &'static str {
+    match kind {
+        TrackWriteErrorKind::FolderCreate => "cannot create output folders",
+        TrackWriteErrorKind::FileCreate => "cannot create output file",
+        TrackWriteErrorKind::FileWrite => "cannot write output file",
+    }
+}
+
+fn download_problem(kind: &TrackDownloadErrorKind) -> &'static str {
+    match kind {
+        TrackDownloadErrorKind::AudioKey => "cannot get audio key",
+        TrackDownloadErrorKind::AudioFile => "cannot get audio file",
+        TrackDownloadErrorKind::TrackFile => "cannot get track file audio",
+        TrackDownloadErrorKind::InvalidAudio => "decryption produced invalid audio",
+        TrackDownloadErrorKind::Timeout => "download stalled",
+        TrackDownloadErrorKind::Unavailable => "track is not available",
+    }
+}
+
+// Tracks of the run whose format expands to the same file are told apart up front, instead of the later ones being
+// skipped as if they had been downloaded before, because the first one just wrote the file
+fn claim_output_file(
+    context: &TrackContext<'_>,
+    track_id: &lsc::SpotifyId,
+    output_file: OutputFile,
+    indent: &str,
+    lines: &mut Vec<String>,
+) -> OutputFile {
+    let track_id = track_id.to_base62().unwrap();
+    let mut output_files = context.output_files.lock().unwrap();
+    let mut claimed = output_file.clone();
+
+    for number in 2.. {
+        match output_files.get(&claimed.file) {
+            Some(owner) if *owner != track_id => claimed = output_file.numbered(number),
+            _ => break,
+        }
+    }
+
+    if claimed.file != output_file.file {
+        lines.push(format!(
+            "{}   - {}: track {} is also written to \"{}\", writing to \"{}\" instead",
+            indent,
+            "warning".yellow().bold(),
+            output_files[&output_file.file],
+            output_file.file,
+            claimed.file
+        ));
+    }
+
+    output_files.insert(claimed.file.clone(), track_id);
+    claimed
+}
+
+// With --verify-existing, files left broken by an earlier run are downloaded again instead of skipped
+fn skip_existing(opts: &UserParams, file: &str, indent: &str, lines: &mut Vec<String>) -> bool {
+    if !path::Path::new(file).exists() {
+        return false;
+    }
+
+    if opts.verify_existing {
+        if let Some(problem) = manifest::check_file(path::Path::new(file)) {
+            lines.push(format!(
+                "{}   - {}: output file \"{}\" is broken: {}, downloading again...",
+                indent,
+                "note".bright_blue().bold(),
+                file,
+                problem
+            ));
+            return false;
+        }
+    }
+
+    lines.push(format!(
+        "{}   - {}: output file \"{}\" already exists, skipping...",
+        indent,
+        "note".bright_blue().bold(),
+        file
+    ));
+    true
+}
+
+// Tracks that failed are left out, the rest keep the order of the playlist or album they came from
+fn write_group_playlists(
+    format: &OutputFormat,
+    paths: manifest::PlaylistPaths,
+    input_groups: &[TrackGroup],
+    playlist_entries: &coll::HashMap<String, manifest::PlaylistEntry>,
+) {
+    for group in input_groups {
+        let Some(name) = &group.name else {
+            continue;
+        };
+
+        let entries = group
+            .tracks
+            .iter()
+            .filter_map(|track_id| playlist_entries.get(&track_id.to_base62().unwrap()))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if entries.is_empty() {
+            continue;
+        }
+
+        let output = path::Path::new(&format.root()).join(format!("{}.m3u8", format.sanitizer.sanitize(name)));
+
+        match manifest::write_playlist_entries(&entries, &output, manifest::PlaylistFormat::M3u, paths) {
+            Ok(()) => say!(
+                "\n{} Wrote {} tracks to \"{}\"",
+                "=>".green().bold(),
+                entries.len().to_string().bold(),
+                output.display()
+            ),
+            Err(err) => say!(
+                "\n{}: cannot write playlist {}: {}",
+                "warning".yellow().bold(),
+                output.display(),
+                err
+            ),
+        }
+    }
+}
+
+// Records what the synced folder holds now, and takes out the files of tracks that left the playlist since the last
+// sync. Without --prune or --archive-removed they're only reported
+fn finish_sync(
+    sync: &playlist_sync::SyncParams,
+    playlist: &str,
+    input_groups: &[TrackGroup],
+    synced_files: Vec<(String, String)>,
+    complete: bool,
+) {
+    let dir = path::Path::new(&sync.dir);
+
+    let mut sync_manifest = match playlist_sync::SyncManifest::load(dir) {
+        Ok(sync_manifest) => sync_manifest,
+        Err(err) => {
+            say!("{}: cannot read sync manifest: {}", "warning".yellow().bold(), err);
+            return;
+        }
+    };
+
+    sync_manifest.playlist = playlist.to_owned();
+
+    for (track_id, file) in synced_files {
+        // Tracks found in another folder, like an --also-have one, aren't part of the synced folder
+        if let Ok(file) = path::Path::new(&file).strip_prefix(dir) {
+            sync_manifest
+                .tracks
+                .insert(track_id, file.to_string_lossy().into_owned());
+        }
+    }
+
+    let playlist_ids: coll::HashSet<String> = input_groups
+        .iter()
+        .flat_map(|group| group.tracks.iter())
+        .map(|id| id.to_base62().unwrap())
+        .collect();
+
+    let removed: Vec<(String, String)> = sync_manifest
+        .tracks
+        .iter()
+        .filter(|(track_id, _)| complete && !playlist_ids.contains(*track_id))
+        .map(|(track_id, file)| (track_id.clone(), file.clone()))
+        .collect();
+
+    if !removed.is_empty() {
+        say!("\n{} Tracks removed from the playlist:", "=>".green().bold());
+    }
+
+    // With --trash, pruned files go to the trash folder of this run, like archived ones go to theirs
+    let trash_dir = match sync.trash_retention.map(|_| trash::run_dir()) {
+        Some(Ok(trash_dir)) => Some(trash_dir),
+        Some(Err(err)) => {
+            say!("{}: cannot find the trash: {}", "warning".yellow().bold(), err);
+            return;
+        }
+        None => None,
+    };
+
+    let archive_dir = trash_dir
+        .as_deref()
+        .or(sync.archive_dir.as_deref().map(path::Path::new));
+    let mut num_removed: usize = 0;
+
+    for (track_id, file) in removed {
+        if !sync.prune && archive_dir.is_none() {
+            say!(" {} \"{}\", kept", "->".yellow().bold(), file);
+            continue;
+        }
+
+        match playlist_sync::remove_file(dir, &file, archive_dir) {
+            Ok(()) => {
+                say!(
+                    " {} \"{}\", {}",
+                    "->".yellow().bold(),
+                    file,
+                    if trash_dir.is_some() {
+                        "trashed"
+                    } else if sync.prune {
+                        "deleted"
+                    } else {
+                        "archived"
+                    }
+                );
+            }
+            // Files deleted by hand are gone already
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => {
+                say!(
+                    " {} \"{}\": {}: {}",
+                    "->".yellow().bold(),
+                    file,
+                    "warning".yellow().bold(),
+                    err
+                );
+                continue;
+            }
+        }
+
+        sync_manifest.tracks.remove(&track_id);
+        num_removed += 1;
+    }
+
+    if let Err(err) = sync_manifest.save(dir) {
+        say!("{}: cannot write sync manifest: {}", "warning".yellow().bold(), err);
+        return;
+    }
+
+    if let Some(retention) = sync.trash_retention {
+        if let Err(err) = trash::empty(Some(retention)) {
+            say!("{}: cannot empty expired trash: {}", "warning".yellow().bold(), err);
+        }
+    }
+
+    say!(
+        "\n{} \"{}\" is in sync: {} tracks, {} removed",
+        "=>".green().bold(),
+        sync.dir,
+        sync_manifest.tracks.len(),
+        num_removed
+    );
+}
+
+fn record_batch_result(batch: &mut Option<batch::Batch>, track_id: &lsc::SpotifyId, result: batch::TrackResult) {
+    if let Some(batch) = batch {
+        if let Err(err) = batch.record(&track_id.to_base62().unwrap(), result) {
+            say!(
+                "   - {}: cannot save session {}: {}",
+                "warning".yellow().bold(),
+                batch.name,
+                err
+            );
+        }
+    }
+}
+
+async fn resolve_input_tracks(
+    session: &lsc::Session,
+    opts: &UserParams,
+    rejects: &mut RejectsFile,
+    metadata_cache: &metadata::MetadataCache,
+) -> Vec<TrackGroup> {
+    say!("\n{} Input resources:", "=>".green().bold());
+
+    let mut input_resources = Vec::<InputResource>::new();
+    let mut collections = Vec::<LibraryCollection>::new();
+    let mut profiles = Vec::<UserProfile>::new();
+
+    for line in &opts.input {
+        if let Ok(res) = get_resource_from_line(line) {
+            say!(
+                " {} {}: {}",
+                "->".yellow().bold(),
+                res.kind,
+                &res.id.to_base62().unwrap()
+            );
+            input_resources.push(res);
+        //
+        } else if let Some(link) = external::ExternalLink::from_line(line) {
+            match link.resolve(session).await {
+                Ok(res) => {
+                    say!(
+                        " {} {}: {} (matched from {} {} {})",
+                        "->".yellow().bold(),
+                        res.kind,
+                        &res.id.to_base62().unwrap(),
+                        link.service,
+                        link.kind,
+                        link.id
+                    );
+                    input_resources.push(res);
+                }
+                Err(err) => {
+                    say!(
+                        "{}: cannot match {} {} {}: {}, skipping...",
+                        " -> warning".yellow().bold(),
+                        link.service,
+                        link.kind,
+                        link.id.bold(),
+                        err
+                    );
+                    rejects.add(line, &format!("cannot match {} {}: {}", link.service, link.kind, err));
+                }
+            }
+        //
+        } else if let Some(keyword) = PlaylistKeyword::from_line(line) {
+            match keyword.find_playlist(session).await {
+                Ok(res) => {
+                    say!(
+                        " {} {}: {} ({})",
+                        "->".yellow().bold(),
+                        res.kind,
+                        &res.id.to_base62().unwrap(),
+                        keyword
+                    );
+                    input_resources.push(res);
+                }
+                Err(err) => {
+                    say!(
+                        "{}: cannot find {} playlist: {}, skipping...",
+                        " -> warning".yellow().bold(),
+                        keyword.to_string().bold(),
+                        err
+                    );
+                    rejects.add(line, &format!("cannot find playlist: {}", err));
+                }
+            }
+        //
+        } else if let Some(collection) = LibraryCollection::from_line(line) {
+            say!(" {} {}", "->".yellow().bold(), collection);
+            collections.push(collection);
+        //
+        } else if let Some(profile) = UserProfile::from_line(line) {
+            say!(" {} {}", "->".yellow().bold(), profile);
+            profiles.push(profile);
+        //
+        } else {
+            say!(
+                "{}: unrecognized input: {}, skipping...",
+                " -> warning".yellow().bold(),
+                line.bold()
+            );
+            rejects.add(line, "unrecognized input");
+        }
+    }
+
+    if let Some(params) = &opts.csv_match {
+        match_csv_rows(session, params, &mut input_resources, rejects).await;
+    }
+
+    let mut input_groups = Vec::<TrackGroup>::new();
+
+    for res in &input_resources {
+        match res.get_track_groups(session, metadata_cache, &opts.artist_scope).await {
+            Ok(groups) => input_groups.extend(groups),
+            Err(err) => {
+                say!(
+                    "{}: cannot get metadata for {} {}: {}, skipping...",
+                    "warning".yellow().bold(),
+                    res.kind,
+                    res.id.to_base62().unwrap(),
+                    err
+                );
+                rejects.add(
+                    &format!("spotify:{}:{}", res.kind, res.id.to_base62().unwrap()),
+                    &format!("cannot get metadata: {}", err),
+                );
+            }
+        }
+    }
+
+    if opts.liked {
+        say!(" {} liked songs and saved albums", "->".yellow().bold());
+        collections.extend([LibraryCollection::LikedSongs, LibraryCollection::SavedAlbums]);
+    }
+
+    for collection in &collections {
+        match collection.get_track_groups(session, metadata_cache).await {
+            Ok(groups) => input_groups.extend(groups),
+            Err(err) => {
+                say!(
+                    "{}: cannot get your {}: {}, skipping...",
+                    "warning".yellow().bold(),
+                    collection,
+                    err
+                );
+                rejects.add(&collection.to_string(), &format!("cannot get library: {}", err));
+            }
+        }
+    }
+
+    for profile in &profiles {
+        match profile.get_track_groups(session, metadata_cache).await {
+            Ok(groups) => input_groups.extend(groups),
+            Err(err) => {
+                say!(
+                    "{}: cannot get playlists of {}: {}, skipping...",
+                    "warning".yellow().bold(),
+                    profile,
+                    err
+                );
+                rejects.add(
+                    &format!("spotify:user:{}", profile.username),
+                    &format!("cannot get playlists: {}", err),
+                );
+            }
+        }
+    }
+
+    if let Some(seed) = &opts.radio {
+        match get_resource_from_line(seed) {
+            Ok(res) if matches!(res.kind, ResourceKind::Track | ResourceKind::Artist) => {
+                match res.get_radio_tracks(session, opts.radio_count).await {
+                    Ok(tracks) => {
+                        say!(
+                            " {} radio: {} tracks seeded from {} {}",
+                            "->".yellow().bold(),
+                            tracks.len(),
+                            res.kind,
+                            res.id.to_base62().unwrap()
+                        );
+
+                        input_groups.push(TrackGroup {
+                            title: Some(format!(
+                                "radio: {} {} ({} requested)",
+                                res.kind,
+                                res.id.to_base62().unwrap(),
+                                opts.radio_count
+                            )),
+                            name: None,
+                            tracks,
+                            radio: Some(manifest::RadioSeed {
+                                seed: format!("spotify:{}:{}", res.kind, res.id.to_base62().unwrap()),
+                                count: opts.radio_count,
+                            }),
+                        });
+                    }
+                    Err(err) => {
+                        say!(
+                            "{}: cannot get radio for {} {}: {}, skipping...",
+                            "warning".yellow().bold(),
+                            res.kind,
+                            res.id.to_base62().unwrap(),
+                            err
+                        );
+                        rejects.add(seed, &format!("cannot get radio: {}", err));
+                    }
+                }
+            }
+            _ => {
+                say!(
+                    "{}: radio seed must be a track or artist: {}, skipping...",
+                    " -> warning".yellow().bold(),
+                    seed.bold()
+                );
+                rejects.add(seed, "radio seed must be a track or artist");
+            }
+        }
+    }
+
+    // A track that shows up more than once is only processed the first time
+    if opts.dedup != DedupMode::None {
+        // Keyed on the bare id, ids given on their own have no item type while the ones from albums and playlists do
+        let mut seen = coll::HashSet::<u128>::new();
+
+        for group in &mut input_groups {
+            group.tracks.retain(|id| seen.insert(id.id));
+        }
+    }
+
+    input_groups.retain(|group| !group.tracks.is_empty());
+
+    // Track metadata is asked for in batches here, instead of once for every track when it's ripped
+    for group in &input_groups {
+        for err in metadata_cache.prefetch_tracks(session, &group.tracks).await {
+            say!(
+                "{}: cannot get a batch of track metadata: {}, getting those tracks one at a time...",
+                "warning".yellow().bold(),
+                err
+            );
+        }
+    }
+
+    input_groups
+}
+
+async fn match_csv_rows(
+    session: &lsc::Session,
+    params: &matching::CsvMatchParams,
+    input_resources: &mut Vec<InputResource>,
+    rejects: &mut RejectsFile,
+) {
+    let rows = match matching::read_rows(params) {
+        Ok(rows) => rows,
+        Err(err) => {
+            say!(
+                "{}: cannot read csv file {}: {}",
+                "error".red().bold(),
+                params.file,
+                err
+            );
+            proc::exit(1);
+        }
+    };
+
+    let mut review = match matching::ReviewWriter::create(&params.review) {
+        Ok(review) => review,
+        Err(err) => {
+            say!(
+                "{}: cannot create review file {}: {}",
+                "error".red().bold(),
+                params.review,
+                err
+            );
+            proc::exit(1);
+        }
+    };
+
+    let mut num_rejected: usize = 0;
+
+    for row in &rows {
+        let (reason, candidate) = match row.find_match(session).await {
+            Ok(Some(found)) if found.confidence >= params.threshold => match lsc::SpotifyId::from_uri(&found.hit.uri) {
+                Ok(id) => {
+                    say!(
+                        " {} track: {} (matched \"{}\", {:.0}%)",
+                        "->".yellow().bold(),
+                        id.to_base62().unwrap(),
+                        row.query(),
+                        found.confidence * 100.0
+                    );
+
+                    input_resources.push(InputResource {
+                        kind: ResourceKind::Track,
+                        id,
+                    });
+                    continue;
+                }
+                Err(err) => (format!("invalid search result uri: {}", err), Some(found)),
+            },
+            Ok(Some(found)) => (
+                format!("low confidence ({:.0}%)", found.confidence * 100.0),
+                Some(found),
+            ),
+            Ok(None) => ("no search results".to_owned(), None),
+            Err(err) => (format!("search failed: {}", err), None),
+        };
+
+        say!(
+            "{}: cannot match row {} \"{}\": {}, skipping...",
+            " -> warning".yellow().bold(),
+            row.line,
+            row.query().bold(),
+            reason
+        );
+
+        rejects.add(&format!("{}:{}: {}", params.file, row.line, row.query()), &reason);
+
+        if let Err(err) = review.reject(row, &reason, candidate.as_ref()) {
+            say!(
+                "{}: cannot write review file {}: {}",
+                " -> warning".yellow().bold(),
+                params.review,
+                err
+            );
+        }
+
+        num_rejected += 1;
+    }
+
+    if num_rejected > 0 {
+        say!(
+            " {} {} rows need review, see \"{}\"",
+            "->".yellow().bold(),
+            num_rejected,
+            params.review
+        );
+    }
+}
+
+// Keeps a tab separated record of every input that couldn't be resolved, along with the reason. Rejects of earlier runs
+// are kept, the file is only appended to
+struct RejectsFile {
+    path: String,
+    file: Option<fs::File>,
+    count: usize,
+}
+
+impl RejectsFile {
+    fn create(path: Option<&str>) -> io::Result<RejectsFile> {
+        Ok(RejectsFile {
+            path: path.unwrap_or_default().to_owned(),
+            file: path
+                .map(|path| fs::OpenOptions::new().create(true).append(true).open(path))
+                .transpose()?,
+            count: 0,
+        })
+    }
+
+    fn add(&mut self, input: &str, reason: &str) {
+        self.count += 1;
+
+        if let Some(file) = &mut self.file {
+            if let Err(err) = writeln!(file, "{}\t{}", input, reason) {
+                say!(
+                    "{}: cannot write rejects file {}: {}",
+                    " -> warning".yellow().bold(),
+                    self.path,
+                    err
+                );
+            }
+        }
+    }
+}
+
+struct UserParams {
+    user: String,
+    pass: String,
+    credentials_file: Option<String>,
+    token: Option<String>,
+    oauth: bool,
+    proxy: Option<reqwest::Url>,
+    device_id: Option<String>,
+    client_id: Option<String>,
+    format: OutputFormat,
+    input: Vec<String>,
+    csv_match: Option<matching::CsvMatchParams>,
+    rejects: Option<String>,
+    blocklist: Option<String>,
+    download_archive: Option<String>,
+    also_have: Vec<String>,
+    session: Option<String>,
+    radio: Option<String>,
+    radio_count: usize,
+    track_timeout: Option<time::Duration>,
+    max_runtime: Option<time::Duration>,
+    artist_scope: ArtistScope,
+    pipe: Option<String>,
+    upgrade: Option<String>,
+    search: Option<String>,
+    complete_albums: Option<String>,
+    bench: Option<usize>,
+    reproducible: bool,
+    preview: bool,
+    quality: AudioQuality,
+    strict_quality: bool,
+    encode: Option<transcode::Encoding>,
+    verify_existing: bool,
+    jobs: usize,
+    retries: usize,
+    json: bool,
+    liked: bool,
+    write_playlist: bool,
+    playlist_paths: manifest::PlaylistPaths,
+    tag_featuring: Featuring,
+    audio_info_tags: bool,
+    rate_limit: Option<u64>,
+    sleep_between: Option<(time::Duration, time::Duration)>,
+    lyrics: bool,
+    lyrics_files: bool,
+    write_art: Option<CoverSize>,
+    dedup: DedupMode,
+    sync: Option<playlist_sync::SyncParams>,
+}
+
+fn parse_opts() -> Result<UserParams, String> {
+    let args: Vec<String> = env::args().collect();
+    let program = args[0].clone();
+
+    let mut opts = getopts::Options::new();
+
+    opts.optflag("h", "help", "print the help menu");
+    opts.optflag("v", "version", "show copyright and version information");
+    opts.optflag(
+        "",
+        "json",
+        "print an event per track as a line of json instead of text, which goes to stderr. Together with --version, print version information as json",
+    );
+
+    opts.optopt(
+        "",
+        "config",
+        "read default options from this file instead of ~/.config/rippify/config.toml. It can set user, format, output_dir, quality, jobs, device_id, client_id, canonical_artists and sort_articles, options given here take precedence. An [artist_aliases] table maps artist ids or names (matched regardless of case and accents) to the name used for {author}, and a [sort_names] table maps artist or album names to what {author_sort} and {album_sort} give for them, e.g. a latin name for one in another script",
+        "FILE",
+    );
+    opts.optopt(
+        "u",
+        "user",
+        "user login name. Without a password, the credentials cached by a previous login are used",
+        "USER",
+    );
+    opts.optopt(
+        "p",
+        "pass",
+        "user password, only needed the first time: the login is cached and reused by later runs",
+        "PASS",
+    );
+    opts.optopt(
+        "",
+        "credentials-file",
+        "log in with a stored librespot credentials.json (as cached by spotifyd or librespot) instead of a user and password",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "token",
+        "log in with an oauth access token that has the streaming scope",
+        "TOKEN",
+    );
+    opts.optflag(
+        "",
+        "oauth",
+        "log in from the browser instead of giving a password, the login is cached for later runs",
+    );
+    opts.optopt(
+        "",
+        "proxy",
+        "http proxy to connect to Spotify through, e.g. http://proxy:8080. HTTPS_PROXY is used when not given",
+        "URL",
+    );
+    opts.optopt(
+        "",
+        "device-id",
+        "device id to log in as. A new one is made up for every run by default",
+        "ID",
+    );
+    opts.optopt(
+        "",
+        "client-id",
+        "Spotify client id to log in with, librespot's own is used by default",
+        "ID",
+    );
+    opts.optopt(
+        "f",
+        "format",
+        "output format to use. {author}/{album}/{name}.{ext} is used by default. Available format specifiers are: {author}, {author_sort}, {artists}, {album_artist}, {composer}, {album}, {album_sort}, {name}, {ext}, {disc_number}, {disc}, {track_number}, {track_num}, {year}, {id} and {bitrate}. {field?...} only expands to its contents when the field is present, e.g. {disc_number?Disc {disc_number}/} is left out for single disc albums. Fields take modifiers after a colon: a width to pad numbers with zeros to, lower, upper or slug, e.g. {track_num:03} or {album:lower:slug}. When a track has more than one artist, {author} is picked among them by --author-policy (track metadata still lists every artist).",
+        "FMT",
+    );
+    opts.optopt(
+        "o",
+        "output",
+        "directory the output format is relative to, the current one by default",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "author-policy",
+        "how {author} is picked when a track has more than one artist: first, album-artist, join-all or primary-by-role. first is used by default",
+        "POLICY",
+    );
+    opts.optopt(
+        "",
+        "sort-articles",
+        "comma separated articles that {author_sort} and {album_sort} move to the end of names, e.g. the,a,an,le,la,les,l',die,der,das. the,a,an is used by default",
+        "LIST",
+    );
+    opts.optopt(
+        "",
+        "sanitize",
+        "characters to replace in every field of the output path: posix (only \"/\"), windows (also <>:\"\\|?*, trailing dots and device names) or strict (plain ascii, accents are dropped). posix is used by default",
+        "POLICY",
+    );
+    opts.optopt(
+        "",
+        "sanitize-replacement",
+        "character replaced ones are written as, a space for posix and \"_\" otherwise by default",
+        "CHAR",
+    );
+    opts.optflag(
+        "",
+        "audio-info-tags",
+        "also tag ogg files with their sample rate, channel count and nominal bitrate. These always go in album manifests",
+    );
+    opts.optflag(
+        "",
+        "lyrics",
+        "tag ogg files with the lyrics of the track, with timestamps when they are synced",
+    );
+    opts.optflag(
+        "",
+        "lyrics-files",
+        "also write synced lyrics to a .lrc file next to each track. Implies --lyrics",
+    );
+    opts.optflag(
+        "",
+        "write-art",
+        "save the album cover as cover.jpg in each album folder",
+    );
+    opts.optopt(
+        "",
+        "art-size",
+        "size of the cover saved by --write-art: small, medium, large, xlarge or largest. The largest one the album has is used by default, or when it doesn't have the one asked for",
+        "SIZE",
+    );
+    opts.optopt(
+        "",
+        "featuring-paths",
+        "what to do with \"(feat. X)\" credits in track names used for {name}: keep, strip or normalize (rewrite every variant as \"(feat. X)\"). keep is used by default",
+        "MODE",
+    );
+    opts.optopt(
+        "",
+        "featuring-tags",
+        "same as --featuring-paths, for the title tag",
+        "MODE",
+    );
+    opts.optflag(
+        "",
+        "canonical-artists",
+        "file each artist under the name it was first written with in this library, kept in .rippify-artists.json, even when later credited differently",
+    );
+    opts.optopt(
+        "",
+        "csv",
+        "match mode: csv file with the artist/title pairs to look up",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "csv-columns",
+        "match mode: comma separated artist, title and (optional) album columns, given by header name or zero based index. artist,title,album is used by default",
+        "COLS",
+    );
+    opts.optopt(
+        "",
+        "min-confidence",
+        "match mode: minimum match confidence (0.0 to 1.0) for a search result to be downloaded. 0.75 is used by default",
+        "NUM",
+    );
+    opts.optopt(
+        "",
+        "review",
+        "match mode: file to write rejected rows to. <csv name>.review.csv is used by default",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "rejects",
+        "file to append inputs that could not be resolved to, one per line along with the reason",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "session",
+        "name of a batch session to record progress in. An existing session is resumed from where it left off",
+        "NAME",
+    );
+    opts.optflag("", "liked", "download the liked songs and saved albums of your account");
+    opts.optopt(
+        "",
+        "radio",
+        "download recommendations seeded from the given track or artist URI",
+        "URI",
+    );
+    opts.optopt(
+        "",
+        "count",
+        "number of tracks to get with --radio. 50 is used by default",
+        "NUM",
+    );
+    opts.optopt(
+        "",
+        "iterations",
+        "bench mode: number of tracks to time. 5 is used by default",
+        "NUM",
+    );
+    opts.optopt(
+        "",
+        "blocklist",
+        "never download the tracks in this file, one track link, uri, id or ISRC per line",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "download-archive",
+        "skip tracks whose id is in this file, and add the id of every track written to it. Unlike existing files it doesn't depend on the output format",
+        "FILE",
+    );
+    opts.optmulti(
+        "",
+        "also-have",
+        "treat tracks found in this directory as already downloaded, matched by the ISRC or the artist and title in their tags. Reads flac, ogg, opus and mp3 files, can be given more than once",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "pipe",
+        "also write every finished track to this file or named pipe, to listen to tracks while the rest download",
+        "PATH",
+    );
+    opts.optopt(
+        "",
+        "library",
+        "stats mode: library folder to report on. The current folder is used by default",
+        "DIR",
+    );
+    opts.optopt(
+        "j",
+        "jobs",
+        "number of tracks to download at the same time. 1 is used by default",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "retries",
+        "retry failed downloads this many times, waiting longer after each attempt. Tracks that aren't available aren't retried. 3 is used by default",
+        "N",
+    );
+    opts.optopt(
+        "",
+        "quality",
+        "audio quality to download: low (96 kbps), normal (160 kbps) or high (320 kbps). high is used by default, tracks that aren't available at it are downloaded at the closest quality",
+        "QUALITY",
+    );
+    opts.optflag(
+        "",
+        "strict-quality",
+        "fail tracks that aren't available at the requested quality instead of downloading them at another one",
+    );
+    opts.optopt(
+        "",
+        "encode",
+        "convert tracks to mp3, flac or opus with ffmpeg, which has to be installed. {ext} follows the encoding and tags are carried over",
+        "FORMAT",
+    );
+    opts.optflag(
+        "",
+        "verify-existing",
+        "check that existing ogg files are complete before skipping them, and download them again if not",
+    );
+    opts.optflag(
+        "",
+        "preview",
+        "only download the 30 second preview clip of every track, as mp3 files",
+    );
+    opts.optflag(
+        "",
+        "reproducible",
+        "give written files a fixed modification time (SOURCE_DATE_EPOCH, or the album's release date), so ripping the same track twice gives identical files",
+    );
+    opts.optflag(
+        "",
+        "write-playlist",
+        "write an m3u8 file for every playlist and album given into the library root, named after it and listing its tracks in their original order",
+    );
+    opts.optopt(
+        "",
+        "playlist",
+        "playlist-from-dir mode: playlist file to write, as xspf when it ends in .xspf and m3u8 otherwise. <DIR>/playlist.m3u8 is used by default",
+        "FILE",
+    );
+    opts.optopt(
+        "",
+        "playlist-paths",
+        "how tracks are listed in written playlists: relative (to the playlist's folder) or absolute. relative is used by default",
+        "MODE",
+    );
+    opts.optflag(
+        "",
+        "quick",
+        "verify mode: only check files against the size and checksum in their album manifest, without decoding the audio",
+    );
+    opts.optflag(
+        "",
+        "prune",
+        "sync mode: delete the files of tracks that were removed from the playlist",
+    );
+    opts.optflag(
+        "",
+        "trash",
+        "sync mode: with --prune, move removed files into the trash instead of deleting them, until \"trash empty\" or the end of --trash-retention",
+    );
+    opts.optopt(
+        "",
+        "trash-retention",
+        "sync mode: how long trashed files are kept before a sync deletes them, e.g. 12h or 30d. 30d is used by default",
+        "TIME",
+    );
+    opts.optopt(
+        "",
+        "archive-removed",
+        "sync mode: move the files of tracks that were removed from the playlist into this folder instead of deleting them",
+        "DIR",
+    );
+    opts.optopt(
+        "",
+        "dedup",
+        "how tracks listed more than once are found: id (the same track), isrc (the same recording, even on another album, by name, artist and duration when there's no isrc) or none, which needs --jobs 1. id is used by default",
+        "MODE",
+    );
+    opts.optopt(
+        "",
+        "artist-scope",
+        "comma separated releases artist inputs are expanded to: albums, singles, appears-on, compilations or all. albums,singles is used by default",
+        "LIST",
+    );
+    opts.optflag(
+        "",
+        "top-tracks",
+        "expand artist inputs to the artist's top tracks in your country instead of their releases",
+    );
+    opts.optopt(
+        "",
+        "max-runtime",
+        "stop starting new tracks once the run has taken this long, e.g. 2h, and exit after the ones in progress. Together with --session, the next run picks up where this one stopped",
+        "TIME",
+    );
+    opts.optopt(
+        "",
+        "rate-limit",
+        "download no faster than this many bytes per second in total, e.g. 500k or 2m. Unlimited by default",
+        "RATE",
+    );
+    opts.optopt(
+        "",
+        "sleep-between",
+        "wait a random time in this range before starting each track, e.g. 5..30s. Only the first --jobs tracks start right away",
+        "MIN..MAX",
+    );
+    opts.optopt(
+        "",
+        "track-timeout",
+        "give up on a track when its download makes no progress for this long, e.g. 90s, 10m or 1h. Disabled by default",
+        "TIME",
+    );
+
+    let matches = opts.parse(&args[1..]).map_err(|e| e.to_string())?;
+    let mut input = matches.free.clone();
+    let match_mode = input.first().is_some_and(|cmd| cmd == "match");
+
+    if match_mode {
+        input.remove(0);
+    }
+
+    if matches.opt_present("v") && matches.opt_present("json") {
+        print_version_json();
+        proc::exit(0);
+    }
+
+    if matches.opt_present("v") {
+        print_version();
+        proc::exit(0);
+    }
+
+    let config = match matches.opt_str("config") {
+        Some(file) => {
+            config::Config::load(path::Path::new(&file)).map_err(|e| format!("cannot read config {}: {}", file, e))?
+        }
+        None => match config::Config::default_path().filter(|file| file.exists()) {
+            Some(file) => {
+                config::Config::load(&file).map_err(|e| format!("cannot read config {}: {}", file.display(), e))?
+            }
+            None => config::Config::default(),
+        },
+    };
+
+    // The playlist is downloaded like any other input, only into the synced folder
+    let sync = if input.first().is_some_and(|cmd| cmd == "sync") {
+        let playlist = input
+            .get(1)
+            .filter(|line| {
+                matches!(
+                    get_resource_from_line(line),
+                    Ok(InputResource {
+                        kind: ResourceKind::Playlist,
+                        ..
+                    })
+                )
+            })
+            .cloned()
+            .ok_or("sync needs a playlist, e.g. \"sync spotify:playlist:ID [DIR]\"")?;
+
+        if matches.opt_present("session") {
+            return Err("sync can't be used with --session".to_owned());
+        }
+
+        if matches.opt_present("prune") && matches.opt_present("archive-removed") {
+            return Err("--prune and --archive-removed can't be used together".to_owned());
+        }
+
+        let dir = input.get(2).cloned().unwrap_or(".".to_owned());
+        input = vec![playlist];
+
+        if matches.opt_present("trash") && !matches.opt_present("prune") {
+            return Err("--trash only applies to --prune".to_owned());
+        }
+
+        let trash_retention = match matches.opt_str("trash-retention") {
+            Some(duration) => Some(parse_duration(&duration).ok_or(format!("invalid trash retention: {}", duration))?),
+            None => Some(time::Duration::from_secs(30 * 86400)),
+        };
+
+        Some(playlist_sync::SyncParams {
+            dir,
+            prune: matches.opt_present("prune"),
+            archive_dir: matches.opt_str("archive-removed"),
+            trash_retention: trash_retention.filter(|_| matches.opt_present("trash")),
+        })
+    } else {
+        None
+    };
+
+    // Whole albums are downloaded again into the library, the tracks that are there already get skipped
+    let complete_albums = if input.first().is_some_and(|cmd| cmd == "complete-albums") {
+        let root = input.get(1).cloned().unwrap_or(".".to_owned());
+        input.clear();
+
+        Some(root)
+    } else {
+        None
+    };
+
+    let format_string = matches
+        .opt_str("f")
+        .or(config.format)
+        .unwrap_or("{author}/{album}/{name}.{ext}".to_owned());
+
+    let mut format = OutputFormat {
+        // Formats that are already absolute don't need an output directory
+        format_string: match sync
+            .as_ref()
+            .map(|sync| sync.dir.clone())
+            .or(complete_albums.clone())
+            .or(matches.opt_str("o"))
+            .or(config.output_dir)
+        {
+            Some(dir) if !format_string.starts_with('/') => format!("{}/{}", dir.trim_end_matches('/'), format_string),
+            _ => format_string,
+        },
+        author_policy: match matches.opt_str("author-policy") {
+            Some(policy) => AuthorPolicy::from_name(&policy).ok_or(format!("invalid author policy: {}", policy))?,
+            None => AuthorPolicy::First,
+        },
+        artist_names: ArtistNames::new(config.artist_aliases),
+        featuring: match matches.opt_str("featuring-paths") {
+            Some(mode) => Featuring::from_name(&mode).ok_or(format!("invalid featuring mode: {}", mode))?,
+            None => Featuring::Keep,
+        },
+        sanitizer: Sanitizer::new(
+            match matches.opt_str("sanitize") {
+                Some(policy) => {
+                    SanitizePolicy::from_name(&policy).ok_or(format!("invalid sanitize policy: {}", policy))?
+                }
+                None => SanitizePolicy::Posix,
+            },
+            match matches.opt_str("sanitize-replacement") {
+                Some(replacement) if replacement.chars().count() == 1 => replacement.chars().next(),
+                Some(replacement) => return Err(format!("invalid replacement character: {}", replacement)),
+                None => None,
+            },
+        )
+        .map_err(|e| format!("invalid replacement character: {}", e))?,
+        sort_names: SortNames::new(
+            match matches.opt_str("sort-articles") {
+                Some(list) => list
+                    .split(',')
+                    .map(|article| article.trim().to_owned())
+                    .filter(|article| !article.is_empty())
+                    .collect(),
+                None => config
+                    .sort_articles
+                    .unwrap_or(vec!["the".to_owned(), "a".to_owned(), "an".to_owned()]),
+            },
+            config.sort_names,
+        ),
+    };
+
+    let tag_featuring = match matches.opt_str("featuring-tags") {
+        Some(mode) => Featuring::from_name(&mode).ok_or(format!("invalid featuring mode: {}", mode))?,
+        None => Featuring::Keep,
+    };
+
+    if matches.opt_present("canonical-artists") || config.canonical_artists.unwrap_or(false) {
+        format.artist_names = std::mem::take(&mut format.artist_names)
+            .with_canonical(&format.root())
+            .map_err(|e| format!("cannot read canonical artist names: {}", e))?;
+    }
+
+    if input.first().is_some_and(|cmd| cmd == "template") {
+        if input.get(1).map(|cmd| cmd.as_str()) != Some("check") {
+            return Err("unknown template command, only \"template check [FORMAT]\" is available".to_owned());
+        }
+
+        if let Some(format_string) = input.get(2) {
+            format.format_string = format_string.clone();
+        }
+
+        proc::exit(if check_template(&format) { 0 } else { 1 });
+    }
+
+    if input.first().is_some_and(|cmd| cmd == "trash") {
+        if input.get(1).map(|cmd| cmd.as_str()) != Some("empty") {
+            return Err("unknown trash command, only \"trash empty\" is available".to_owned());
+        }
+
+        match trash::empty(None) {
+            Ok(count) => println!("{} Emptied the trash of {} syncs", "=>".green().bold(), count),
+            Err(err) => {
+                println!("{}: cannot empty the trash: {}", "error".red().bold(), err);
+                proc::exit(1);
+            }
+        }
+        proc::exit(0);
+    }
+
+    if input.first().is_some_and(|cmd| cmd == "sessions") {
+        print_sessions();
+        proc::exit(0);
+    }
+
+    if input.first().is_some_and(|cmd| cmd == "stats") {
+        print_library_stats(matches.opt_str("library").as_deref().unwrap_or("."));
+        proc::exit(0);
+    }
+
+    let playlist_paths = match matches.opt_str("playlist-paths") {
+        Some(paths) => manifest::PlaylistPaths::from_name(&paths).ok_or(format!("invalid playlist paths: {}", paths))?,
+        None => manifest::PlaylistPaths::Relative,
+    };
+
+    if input.first().is_some_and(|cmd| cmd == "playlist-from-dir") {
+        let root = input.get(1).map(|dir| dir.as_str()).unwrap_or(".");
+        write_dir_playlist(root, matches.opt_str("playlist").as_deref(), playlist_paths);
+        proc::exit(0);
+    }
+
+    if input.first().is_some_and(|cmd| cmd == "verify") {
+        let ok = verify_library(
+            input.get(1).map(|dir| dir.as_str()).unwrap_or("."),
+            matches.opt_present("quick"),
+        );
+        proc::exit(if ok { 0 } else { 1 });
+    }
+
+    // What to download is picked from the results once logged in
+    let search = if input.first().is_some_and(|cmd| cmd == "search") {
+        let query = input[1..].join(" ");
+        input.clear();
+
+        if query.trim().is_empty() {
+            return Err("nothing to search for".to_owned());
+        }
+
+        Some(query)
+    } else {
+        None
+    };
+
+    let bench = input.first().is_some_and(|cmd| cmd == "bench");
+
+    if bench {
+        input.remove(0);
+
+        if input.is_empty() {
+            return Err("give the tracks, albums or playlists to benchmark with".to_owned());
+        }
+
+        // --count is the number of radio tracks, passing it here would otherwise be ignored without a word
+        if matches.opt_present("count") {
+            return Err("bench takes the number of tracks to time with --iterations".to_owned());
+        }
+    }
+
+    let upgrade = if input.first().is_some_and(|cmd| cmd == "upgrade") {
+        let root = input.get(1).cloned().unwrap_or(".".to_owned());
+        input.clear();
+        Some(root)
+    } else {
+        None
+    };
+
+    if matches.opt_present("h")
+        || (match_mode && !matches.opt_present("csv"))
+        || (!match_mode
+            && upgrade.is_none()
+            && search.is_none()
+            && complete_albums.is_none()
+            && input.is_empty()
+            && !matches.opt_present("session")
+            && !matches.opt_present("radio")
+            && !matches.opt_present("liked"))
+    {
+        print_usage(&program, opts);
+        proc::exit(0);
+    }
+
+    let csv_match = if match_mode {
+        let file = matches.opt_str("csv").unwrap();
+
+        let threshold = match matches.opt_str("min-confidence") {
+            Some(num) => num
+                .parse::<f64>()
+                .ok()
+                .filter(|num| (0.0..=1.0).contains(num))
+                .ok_or(format!("invalid match confidence: {}", num))?,
+            None => matching::MIN_CONFIDENCE,
+        };
+
+        let review = matches
+            .opt_str("review")
+            .unwrap_or(format!("{}.review.csv", file.strip_suffix(".csv").unwrap_or(&file)));
+
+        Some(matching::CsvMatchParams {
+            columns: matches
+                .opt_str("csv-columns")
+                .unwrap_or("artist,title,album".to_owned())
+                .split(',')
+                .map(|col| col.to_owned())
+                .collect(),
+            file,
+            threshold,
+            review,
+        })
+    } else {
+        None
+    };
+
+    if csv_match.as_ref().is_some_and(|params| params.columns.len() < 2) {
+        return Err("--csv-columns needs at least the artist and title columns".to_owned());
+    }
+
+    let radio_count = match matches.opt_str("count") {
+        Some(num) => num
+            .parse::<usize>()
+            .ok()
+            .filter(|num| *num > 0)
+            .ok_or(format!("invalid radio track count: {}", num))?,
+        None => 50,
+    };
+
+    let iterations = match matches.opt_str("iterations") {
+        Some(num) => num
+            .parse::<usize>()
+            .ok()
+            .filter(|num| *num > 0)
+            .ok_or(format!("invalid number of iterations: {}", num))?,
+        None => 5,
+    };
+
+    let track_timeout = match matches.opt_str("track-timeout") {
+        Some(duration) => Some(parse_duration(&duration).ok_or(format!("invalid track timeout: {}", duration))?),
+        None => None,
+    };
+
+    let mut artist_scope = match matches.opt_str("artist-scope") {
+        Some(list) => ArtistScope::from_list(&list).ok_or(format!("invalid artist scope: {}", list))?,
+        None => ArtistScope::default(),
+    };
+    artist_scope.top_tracks = matches.opt_present("top-tracks");
+
+    let art_size = match matches.opt_str("art-size") {
+        Some(size) => CoverSize::from_name(&size).ok_or(format!("invalid art size: {}", size))?,
+        None => CoverSize::Largest,
+    };
+
+    let dedup = match matches.opt_str("dedup") {
+        Some(mode) => DedupMode::from_name(&mode).ok_or(format!("invalid dedup mode: {}", mode))?,
+        None => DedupMode::Id,
+    };
+
+    let rate_limit = match matches.opt_str("rate-limit") {
+        Some(rate) => Some(parse_rate(&rate).ok_or(format!("invalid rate limit: {}", rate))?),
+        None => None,
+    };
+
+    let sleep_between = match matches.opt_str("sleep-between") {
+        Some(range) => Some(parse_duration_range(&range).ok_or(format!("invalid sleep range: {}", range))?),
+        None => None,
+    };
+
+    let proxy = match matches
+        .opt_str("proxy")
+        .or(env::var("HTTPS_PROXY").ok())
+        .or(env::var("https_proxy").ok())
+        .filter(|proxy| !proxy.is_empty())
+    {
+        Some(proxy) => Some(reqwest::Url::parse(&proxy).map_err(|e| format!("invalid proxy {}: {}", proxy, e))?),
+        None => None,
+    };
+
+    let max_runtime = match matches.opt_str("max-runtime") {
+        Some(duration) => Some(parse_duration(&duration).ok_or(format!("invalid max runtime: {}", duration))?),
+        None => None,
+    };
+
+    let jobs = match matches.opt_str("jobs").or(config.jobs.map(|num| num.to_string())) {
+        Some(num) => num
+            .parse::<usize>()
+            .ok()
+            .filter(|num| *num > 0)
+            .ok_or(format!("invalid number of jobs: {}", num))?,
+        None => 1,
+    };
+
+    // Both copies of a track would be written to the same part file at once
+    if dedup == DedupMode::None && jobs > 1 {
+        return Err("--dedup none only works with a single job".to_owned());
+    }
+
+    let retries = match matches.opt_str("retries") {
+        Some(num) => num
+            .parse::<usize>()
+            .map_err(|_| format!("invalid number of retries: {}", num))?,
+        None => 3,
+    };
+
+    let quality = match matches.opt_str("quality").or(config.quality) {
+        Some(quality) => AudioQuality::from_name(&quality).ok_or(format!("invalid quality: {}", quality))?,
+        None => AudioQuality::High,
+    };
+
+    let encode = matches
+        .opt_str("encode")
+        .map(|encoding| transcode::Encoding::from_name(&encoding).ok_or(format!("invalid encoding: {}", encoding)))
+        .transpose()?;
+
+    let user = matches.opt_str("u").or(config.user).unwrap_or_default();
+    let pass = matches.opt_str("p").unwrap_or_default();
+
+    Ok(UserParams {
+        user,
+        pass,
+        credentials_file: matches.opt_str("credentials-file"),
+        token: matches.opt_str("token"),
+        oauth: matches.opt_present("oauth"),
+        proxy,
+        device_id: matches.opt_str("device-id").or(config.device_id),
+        client_id: matches.opt_str("client-id").or(config.client_id),
+        format,
+        input,
+        csv_match,
+        rejects: matches.opt_str("rejects"),
+        blocklist: matches.opt_str("blocklist"),
+        download_archive: matches.opt_str("download-archive"),
+        also_have: matches.opt_strs("also-have"),
+        session: matches.opt_str("session"),
+        radio: matches.opt_str("radio"),
+        radio_count,
+        track_timeout,
+        max_runtime,
+        artist_scope,
+        pipe: matches.opt_str("pipe"),
+        upgrade,
+        search,
+        complete_albums,
+        bench: bench.then_some(iterations),
+        reproducible: matches.opt_present("reproducible"),
+        preview: matches.opt_present("preview"),
+        quality,
+        strict_quality: matches.opt_present("strict-quality"),
+        encode,
+        verify_existing: matches.opt_present("verify-existing"),
+        jobs,
+        retries,
+        json: matches.opt_present("json"),
+        liked: matches.opt_present("liked"),
+        write_playlist: matches.opt_present("write-playlist"),
+        playlist_paths,
+        tag_featuring,
+        audio_info_tags: matches.opt_present("audio-info-tags"),
+        rate_limit,
+        sleep_between,
+        lyrics: matches.opt_present("lyrics") || matches.opt_present("lyrics-files"),
+        lyrics_files: matches.opt_present("lyrics-files"),
+        write_art: matches.opt_present("write-art").then_some(art_size),
+        dedup,
+        sync,
+    })
+}
+
+// librespot makes up a new device id for every session unless one is given, so each run shows up as another device
+fn session_config(opts: &UserParams) -> lsc::SessionConfig {
+    let default = lsc::SessionConfig::default();
+
+    lsc::SessionConfig {
+        proxy: opts.proxy.clone(),
+        device_id: opts.device_id.clone().unwrap_or(default.device_id.clone()),
+        client_id: opts.client_id.clone().unwrap_or(default.client_id.clone()),
+        ..default
+    }
+}
+
+async fn connect_session(
+    credentials: lsc_auth::Credentials,
+    cache: Option<lsc::cache::Cache>,
+    config: lsc::SessionConfig,
+) -> Result<lsc::Session, lsc::Error> {
+    let session = lsc::Session::new(config, cache);
+    session.connect(credentials, true).await?;
+
+    Ok(session)
+}
+
+// Reusable credentials are saved here after a successful login, so later runs don't need a password
+fn open_credentials_cache() -> Result<lsc::cache::Cache, Box<dyn std::error::Error>> {
+    let dir = dirs::config_dir()
+        .ok_or("cannot find user config directory")?
+        .join("rippify");
+
+    Ok(lsc::cache::Cache::new(Some(dir), None, None, None)?)
+}
+
+// Same format librespot writes to its credentials cache, the blob is reusable across clients
+fn read_credentials_file(path: &str) -> Result<lsc_auth::Credentials, Box<dyn std::error::Error>> {
+    let file = fs::File::open(path)?;
+    let credentials: lsc_auth::Credentials = serde_json::from_reader(io::BufReader::new(file))?;
+
+    if credentials.username.is_empty() {
+        return Err("missing username".into());
+    }
+
+    Ok(credentials)
+}
+
+// Durations are given as a number followed by an optional s, m, h or d unit, seconds by default
+fn parse_duration(duration: &str) -> Option<time::Duration> {
+    let duration = duration.trim();
+    let (num, unit) = match duration.find(|c: char| !c.is_ascii_digit()) {
+        Some(pos) => duration.split_at(pos),
+        None => (duration, "s"),
+    };
+
+    let secs = num.parse::<u64>().ok().filter(|num| *num > 0)?;
+
+    match unit {
+        "s" => Some(time::Duration::from_secs(secs)),
+        "m" => Some(time::Duration::from_secs(secs * 60)),
+        "h" => Some(time::Duration::from_secs(secs * 3600)),
+        "d" => Some(time::Duration::from_secs(secs * 86400)),
+        _ => None,
+    }
+}
+
+// Either a single duration or a range like 5..30s, where a unit on the second one also applies to the first
+fn parse_duration_range(range: &str) -> Option<(time::Duration, time::Duration)> {
+    let Some((min, max)) = range.split_once("..") else {
+        let duration = parse_duration(range)?;
+        return Some((duration, duration));
+    };
+
+    let max_unit = max.trim_start_matches(|c: char| c.is_ascii_digit());
+    let min = if min.ends_with(|c: char| c.is_ascii_digit()) {
+        parse_duration(&format!("{}{}", min, max_unit))?
+    } else {
+        parse_duration(min)?
+    };
+
+    Some((min, parse_duration(max)?)).filter(|(min, max)| min <= max)
+}
+
+// Bytes per second, optionally in k or m, as in 500k
+fn parse_rate(rate: &str) -> Option<u64> {
+    let rate = rate.trim().to_lowercase();
+    let (num, multiplier) = match rate.strip_suffix('k') {
+        Some(num) => (num, 1000),
+        None => match rate.strip_suffix('m') {
+            Some(num) => (num, 1_000_000),
+            None => (rate.as_str(), 1),
+        },
+    };
+
+    num.parse::<u64>()
+        .ok()
+        .filter(|num| *num > 0)
+        .map(|num| num * multiplier)
+}
+
+fn print_usage(program: &str, opts: getopts::Options) {
+    let brief = format!(
+        "Usage: {} [OPTIONS] URIs|discover-weekly|release-radar...\n       {} match --csv FILE [OPTIONS]\n       {} sessions\n       {} verify [--quick] [DIR]\n       {} stats [--library DIR]\n       {} upgrade [OPTIONS] [DIR]\n       {} playlist-from-dir [--playlist FILE] [--playlist-paths MODE] [DIR]\n       {} sync [--prune [--trash]|--archive-removed DIR] PLAYLIST [DIR]\n       {} trash empty\n       {} complete-albums [OPTIONS] [DIR]\n       {} search [OPTIONS] QUERY\n       {} bench [--iterations NUM] URIs...\n       {} template check [FORMAT]",
+        program, program, program, program, program, program, program, program, program, program, program, program, program
+    );
+    print!("{}", opts.usage(&brief));
+}
+
+fn print_sessions() {
+    let batches = match batch::Batch::list() {
+        Ok(batches) => batches,
+        Err(err) => {
+            println!("{}: cannot list sessions: {}", "error".red().bold(), err);
+            proc::exit(1);
+        }
+    };
+
+    if batches.is_empty() {
+        println!("{} No sessions found", "=>".green().bold());
+        return;
+    }
+
+    println!("{} Sessions:", "=>".green().bold());
+
+    for batch in batches {
+        println!(
+            " {} {}: {} of {} tracks done, {} failed, {} inputs",
+            "->".yellow().bold(),
+            batch.name.bold(),
+            batch.state.queue.iter().filter(|id| batch.is_finished(id)).count(),
+            batch.state.queue.len(),
+            batch.count(|result| matches!(result, batch::TrackResult::Failed(_))),
+            batch.state.inputs.len()
+        );
+    }
+}
+
+// Times each step of getting a track on its own, so a slow run can be put down to Spotify, the cpu or the disk. Audio
+// is only written to a scratch file in the output folder, which is removed afterwards. Tracks that can't be timed
+// count as failed for the exit code
+async fn run_bench(session: &lsc::Session, downloader: &TrackDownloader, opts: &UserParams, count: usize) -> i32 {
+    let metadata_cache = metadata::MetadataCache::default();
+    let mut rejects = RejectsFile::create(None).unwrap();
+
+    let track_ids = resolve_input_tracks(session, opts, &mut rejects, &metadata_cache)
+        .await
+        .into_iter()
+        .flat_map(|group| group.tracks)
+        .take(count)
+        .collect::<Vec<_>>();
+
+    if track_ids.is_empty() {
+        println!("\n{}: didn't get any tracks, aborting...", "error".red().bold());
+        proc::exit(1);
+    }
+
+    let scratch_file = path::Path::new(&opts.format.root()).join(".rippify-bench.tmp");
+
+    let mut metadata_time = time::Duration::ZERO;
+    let mut download_time = time::Duration::ZERO;
+    let mut tags_time = time::Duration::ZERO;
+    let mut write_time = time::Duration::ZERO;
+    let mut num_tracks: u32 = 0;
+    let mut total_size: u64 = 0;
+
+    println!("\n{} Timing {} tracks:", "=>".green().bold(), track_ids.len());
+
+    for track_id in &track_ids {
+        let started = time::Instant::now();
+
+        let (track, file_id) = match downloader.get_track(track_id, &metadata_cache).await {
+            Ok(track) => track,
+            Err(err) => {
+                println!(
+                    " {} {}: cannot get track: {}, skipping...",
+                    "->".yellow().bold(),
+                    track_id.to_base62().unwrap(),
+                    err
+                );
+                continue;
+            }
+        };
+        let album_info = metadata_cache.album_info(session, &track).await;
+        let track_metadata_time = started.elapsed();
+
+        let started = time::Instant::now();
+
+        let buffer = match downloader.download(&track, &file_id, None).await {
+            Ok((_, buffer)) => buffer,
+            Err(err) => {
+                println!(
+                    " {} {}: {}: {}, skipping...",
+                    "->".yellow().bold(),
+                    track.name,
+                    download_problem(&err.kind),
+                    err.error
+                );
+                continue;
+            }
+        };
+        let track_download_time = started.elapsed();
+
+        let started = time::Instant::now();
+        let comment_header = track_comment_header(&track, &album_info, &opts.tag_featuring, None, None);
+
+        if let Err(err) = replace_header_comment(io::Cursor::new(&buffer), io::sink(), &comment_header, false) {
+            println!(
+                " {} {}: {}: {}, skipping...",
+                "->".yellow().bold(),
+                track.name,
+                tags_problem(&err.kind),
+                err.error
+            );
+            continue;
+        }
+        let track_tags_time = started.elapsed();
+
+        let started = time::Instant::now();
+
+        let written =
+            fs::File::create(&scratch_file).and_then(|mut file| file.write_all(&buffer).and_then(|_| file.sync_all()));
+        let _ = fs::remove_file(&scratch_file);
+
+        if let Err(err) = written {
+            println!(
+                "{}: cannot write to {}: {}",
+                "error".red().bold(),
+                scratch_file.display(),
+                err
+            );
+            proc::exit(1);
+        }
+        let track_write_time = started.elapsed();
+
+        println!(
+            " {} {}: metadata {} ms, download {}, tags {}, write {}",
+            "->".yellow().bold(),
+            track.name,
+            track_metadata_time.as_millis(),
+            throughput(buffer.len() as u64, track_download_time),
+            throughput(buffer.len() as u64, track_tags_time),
+            throughput(buffer.len() as u64, track_write_time)
+        );
+
+        metadata_time += track_metadata_time;
+        download_time += track_download_time;
+        tags_time += track_tags_time;
+        write_time += track_write_time;
+        num_tracks += 1;
+        total_size += buffer.len() as u64;
+    }
+
+    if num_tracks == 0 {
+        println!("\n{}: none of the tracks could be timed", "error".red().bold());
+        return run_exit_code(track_ids.len(), track_ids.len());
+    }
+
+    let decrypt_time = match time_decrypt(total_size) {
+        Ok(decrypt_time) => decrypt_time,
+        Err(err) => {
+            println!("{}: cannot time decryption: {}", "error".red().bold(), err);
+            proc::exit(1);
+        }
+    };
+
+    println!("\n{} Results:", "=>".green().bold());
+    println!(
+        " {} metadata: {} ms per track",
+        "->".yellow().bold(),
+        (metadata_time / num_tracks).as_millis()
+    );
+    println!(
+        " {} download: {} (Spotify and the network)",
+        "->".yellow().bold(),
+        throughput(total_size, download_time)
+    );
+    println!(
+        " {} decrypt: {} (cpu)",
+        "->".yellow().bold(),
+        throughput(total_size, decrypt_time)
+    );
+    println!(
+        " {} tags: {} (cpu)",
+        "->".yellow().bold(),
+        throughput(total_size, tags_time)
+    );
+    println!(
+        " {} write: {} (disk, in {})",
+        "->".yellow().bold(),
+        throughput(total_size, write_time),
+        opts.format.root()
+    );
+
+    run_exit_code(track_ids.len(), track_ids.len() - num_tracks as usize)
+}
+
+fn throughput(size: u64, elapsed: time::Duration) -> String {
+    format!(
+        "{:.1} MB/s",
+        size as f64 / 1_000_000.0 / elapsed.as_secs_f64().max(0.001)
+    )
+}
+
+// Tracks that were only available at a lower bitrate when ripped are downloaded again when 320 kbps is there now.
+// The new file is written next to the old one and renamed over it, so a failed upgrade keeps the original
+async fn upgrade_library(session: &lsc::Session, downloader: &TrackDownloader, opts: &UserParams, root: &str) {
+    let best_quality = format!("{:?}", lsm_audio::AudioFileFormat::OGG_VORBIS_320);
+    let metadata_cache = metadata::MetadataCache::default();
+
+    let dirs = match manifest::AlbumManifest::find_all(path::Path::new(root)) {
+        Ok(dirs) => dirs,
+        Err(err) => {
+            println!("{}: cannot search {}: {}", "error".red().bold(), root, err);
+            proc::exit(1);
+        }
+    };
+
+    let mut num_candidates: usize = 0;
+    let mut num_upgraded: usize = 0;
+
+    println!(
+        "\n{} Looking for tracks below 320 kbps under {}:",
+        "=>".green().bold(),
+        root.bold()
+    );
+
+    for dir in dirs {
+        let mut manifest = match manifest::AlbumManifest::load(&dir) {
+            Ok(Some(manifest)) => manifest,
+            Ok(None) => continue,
+            Err(err) => {
+                println!(
+                    " {} {}: {}: cannot read album.json: {}",
+                    "->".yellow().bold(),
+                    dir.display(),
+                    "warning".yellow().bold(),
+                    err
+                );
                 continue;
             }
         };
 
-        match track_write(buffer_tags, output_file) {
-            Ok(output) => {
-                println!("   - wrote \"{}\"", output);
-                num_completed += 1;
+        for index in 0..manifest.tracks.len() {
+            let entry = &manifest.tracks[index];
+
+            if entry.quality == best_quality {
+                continue;
             }
-            Err(err) => {
-                match err.kind {
-                    TrackWriteErrorKind::FolderCreate => {
-                        print!(
-                            "   - {}: cannot create output folders: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
-                    }
-                    TrackWriteErrorKind::FileCreate => {
-                        println!(
-                            "   - {}: cannot create output file: {}, skipping...",
-                            "warning".yellow().bold(),
-                            err.error
-                        );
+
+            num_candidates += 1;
+            println!(" {} {} ({})", "->".yellow().bold(), entry.name.bold(), entry.id);
+
+            let upgrade =
+                match upgrade_track(session, downloader, &metadata_cache, opts, &dir, entry, &best_quality).await {
+                    Ok(Some(upgrade)) => upgrade,
+                    Ok(None) => {
+                        println!("   - still not available at 320 kbps");
+                        continue;
                     }
-                    TrackWriteErrorKind::FileWrite => {
+                    Err(err) => {
                         println!(
-                            "   - {}: cannot write output file: {}, skipping...",
+                            "   - {}: cannot upgrade track: {}, skipping...",
                             "warning".yellow().bold(),
-                            err.error
+                            err
                         );
+                        continue;
                     }
                 };
-                continue;
+
+            println!("   - upgraded from {} to {}", entry.quality, upgrade.quality);
+            manifest.tracks[index] = upgrade;
+            num_upgraded += 1;
+
+            if let Err(err) = manifest.save(&dir) {
+                println!(
+                    "   - {}: cannot update album manifest: {}",
+                    "warning".yellow().bold(),
+                    err
+                );
             }
-        };
+        }
     }
 
-    println!("\n{} Processed tracks: ", "=>".green().bold(),);
-
+    println!("\n{} Upgraded tracks: ", "=>".green().bold());
+    println!(" {} {} upgraded", "->".yellow().bold(), num_upgraded);
     println!(
-        " {} {} error",
+        " {} {} not upgraded",
         "->".yellow().bold(),
-        input_tracks.len() - num_completed - num_existing
+        num_candidates - num_upgraded
     );
+}
 
-    println!(" {} {} already downloaded", "->".yellow().bold(), num_existing);
+async fn upgrade_track(
+    session: &lsc::Session,
+    downloader: &TrackDownloader,
+    metadata_cache: &metadata::MetadataCache,
+    opts: &UserParams,
+    dir: &path::Path,
+    entry: &manifest::ManifestTrack,
+    best_quality: &str,
+) -> Result<Option<manifest::ManifestTrack>, Box<dyn std::error::Error>> {
+    let id = lsc::SpotifyId::from_base62(&entry.id)?;
+    let (track, file_id) = downloader.get_track(&id, metadata_cache).await?;
+
+    if track_file_quality(&track, &file_id) != best_quality {
+        return Ok(None);
+    }
 
-    println!(" {} {} new", "->".yellow().bold(), num_completed);
+    let (container, buffer) = downloader
+        .download(&track, &file_id, None)
+        .await
+        .map_err(|err| err.error.to_string())?;
 
-    println!(" {} {} total processed", "->".yellow().bold(), input_tracks.len())
-}
+    let audio_sha256 = manifest::sha256(&buffer);
+    let album_info = metadata_cache.album_info(session, &track).await;
+    // Lyrics that can't be fetched are left out rather than holding back the upgrade
+    let lyrics = if opts.lyrics {
+        TrackLyrics::get(session, &track).await.ok().flatten()
+    } else {
+        None
+    };
+    let comment_header = track_comment_header(
+        &track,
+        &album_info,
+        &opts.tag_featuring,
+        lyrics.as_ref(),
+        entry.requested_id.as_deref(),
+    );
+    let (buffer, audio) = track_add_metadata_tags(buffer, &comment_header, container, opts.audio_info_tags)
+        .map_err(|err| err.error.to_string())?;
+
+    let file = dir.join(&entry.file);
+
+    // Transcoded libraries are upgraded in the encoding their files are already in
+    let buffer = match file
+        .extension()
+        .and_then(|extension| transcode::Encoding::from_name(&extension.to_string_lossy()))
+    {
+        Some(encoding) => transcode::transcode(buffer, encoding)
+            .await
+            .map_err(|err| err.error.to_string())?,
+        None => buffer,
+    };
+    let tmp_file = dir.join(format!("{}.tmp", entry.file));
 
-struct UserParams {
-    user: String,
-    pass: String,
-    format: OutputFormat,
-    input: Vec<String>,
-}
+    let mut tmp_write = fs::File::create(&tmp_file)?;
+    tmp_write.write_all(&buffer)?;
 
-fn parse_opts() -> Result<UserParams, getopts::Fail> {
-    let args: Vec<String> = env::args().collect();
-    let program = args[0].clone();
+    if opts.reproducible {
+        tmp_write.set_modified(reproducible_mtime(&track))?;
+    }
 
-    let mut opts = getopts::Options::new();
+    fs::rename(&tmp_file, &file)?;
 
-    opts.optflag("h", "help", "print the help menu");
-    opts.optflag("v", "version", "show copyright and version information");
+    Ok(Some(manifest::ManifestTrack {
+        quality: best_quality.to_owned(),
+        size: buffer.len() as u64,
+        sha256: manifest::sha256(&buffer),
+        audio_sha256,
+        audio,
+        ..entry.clone()
+    }))
+}
 
-    opts.optopt("u", "user", "user login name, required", "USER");
-    opts.optopt("p", "pass", "user password, required", "PASS");
-    opts.optopt(
-        "f",
-        "format",
-        "output format to use. {author}/{album}/{name}.{ext} is used by default. Available format specifiers are: {author}, {album}, {name} and {ext}. Note that when tracks have more that one author, {author} will evaluate only to main one (track metadata will still we written correctly).",
-        "FMT",
-    );
+// Expands the template for a few made up tracks, including awkward ones, so mistakes show up before a long run.
+// Problems in the template itself are errors, the rest are warnings about paths some tracks will get
+fn check_template(format: &OutputFormat) -> bool {
+    let long_title = "Long Title ".repeat(30);
+
+    // label, artists, album artist, album, name, disc number, disc count, track number
+    type Sample<'a> = (&'a str, &'a [&'a str], &'a str, &'a str, &'a str, i32, usize, i32);
+
+    let samples: [Sample; 7] = [
+        (
+            "typical track",
+            &["Radiohead"],
+            "Radiohead",
+            "OK Computer",
+            "Paranoid Android",
+            1,
+            1,
+            2,
+        ),
+        (
+            "several artists",
+            &["Pharrell Williams", "Daft Punk", "Nile Rodgers"],
+            "Daft Punk",
+            "Random Access Memories",
+            "Get Lucky",
+            1,
+            1,
+            8,
+        ),
+        (
+            "multiple discs",
+            &["The Beatles"],
+            "The Beatles",
+            "The Beatles",
+            "Birthday",
+            2,
+            2,
+            1,
+        ),
+        ("missing album", &["Unknown Artist"], "", "", "Untitled", 1, 1, 0),
+        (
+            "unicode",
+            &["Sigur Rós"],
+            "Sigur Rós",
+            "Ágætis byrjun",
+            "Svefn-g-englar",
+            1,
+            1,
+            2,
+        ),
+        (
+            "slash in artist",
+            &["AC/DC"],
+            "AC/DC",
+            "Back in Black",
+            "Hells Bells",
+            1,
+            1,
+            1,
+        ),
+        (
+            "very long title",
+            &["Fiona Apple"],
+            "Fiona Apple",
+            "Tidal",
+            &long_title,
+            1,
+            1,
+            1,
+        ),
+    ];
+
+    println!("{} Checking template \"{}\"", "=>".green().bold(), format.format_string);
+
+    let mut num_errors: usize = 0;
+    let mut num_warnings: usize = 0;
+
+    for spec in regex::Regex::new(r"\{([^{}?]*)")
+        .unwrap()
+        .captures_iter(&format.format_string)
+        .map(|captures| captures[1].to_owned())
+    {
+        let mut modifiers = spec.split(':');
+        let name = modifiers.next().unwrap_or_default();
+
+        if !FORMAT_FIELDS.contains(&name) {
+            println!(
+                " {} {}: unknown field {{{}}}, it will be left in paths as is",
+                "->".yellow().bold(),
+                "error".red().bold(),
+                name
+            );
+            num_errors += 1;
+        }
 
-    let matches = opts.parse(&args[1..])?;
-    let input = matches.free.clone();
+        for modifier in modifiers.filter(|modifier| apply_modifier("", modifier).is_none()) {
+            println!(
+                " {} {}: unknown modifier \"{}\" in {{{}}}, it will be left in paths as is. Available ones are {}",
+                "->".yellow().bold(),
+                "error".red().bold(),
+                modifier,
+                spec,
+                FORMAT_MODIFIERS.join(", ")
+            );
+            num_errors += 1;
+        }
+    }
 
-    if matches.opt_present("v") {
-        print_version();
-        proc::exit(0);
+    if format.format_string.matches('{').count() != format.format_string.matches('}').count() {
+        println!(" {} {}: unbalanced braces", "->".yellow().bold(), "error".red().bold());
+        num_errors += 1;
     }
 
-    if matches.opt_present("h") || !matches.opt_present("u") || !matches.opt_present("p") || input.is_empty() {
-        print_usage(&program, opts);
-        proc::exit(0);
+    if !format.uses_field("ext") {
+        println!(
+            " {} {}: {{ext}} is not used, files won't have an extension",
+            "->".yellow().bold(),
+            "error".red().bold()
+        );
+        num_errors += 1;
     }
 
-    let format = OutputFormat {
-        format_string: matches
-            .opt_str("f")
-            .unwrap_or("{author}/{album}/{name}.{ext}".to_owned()),
-    };
+    if !format.uses_field("name") && !format.uses_field("track_number") && !format.uses_field("track_num") {
+        println!(
+            " {} {}: neither {{name}} nor {{track_number}} is used, tracks of an album will overwrite each other",
+            "->".yellow().bold(),
+            "error".red().bold()
+        );
+        num_errors += 1;
+    }
 
-    let user = matches.opt_str("u").unwrap();
-    let pass = matches.opt_str("p").unwrap();
+    for (label, artists, album_artist, album, name, disc_number, disc_count, track_number) in samples {
+        let fields = TrackFields {
+            author: format
+                .author_policy
+                .pick(artists, Some(album_artist).filter(|artist| !artist.is_empty()), None),
+            artists: artists.iter().map(|artist| artist.to_string()).collect(),
+            album_artist: album_artist.to_owned(),
+            composers: Vec::new(),
+            album: album.to_owned(),
+            name: format.featuring.apply(name),
+            disc_number,
+            disc_count,
+            track_number,
+            // Made up albums get a made up release year, unless there is no album at all
+            year: if album.is_empty() { 0 } else { 1997 },
+            id: "4iV5W9uYEdYUVa79Axb7Rh".to_owned(),
+            bitrate: Some(320),
+        };
 
-    Ok(UserParams {
-        user,
-        pass,
-        format,
-        input,
-    })
-}
+        let output_file = format.expand_fields(&fields, AudioContainer::Ogg.extension());
+        let components = output_file.file.split('/').collect::<Vec<_>>();
+
+        println!(" {} {}", "->".yellow().bold(), label.bold());
+        println!("   - {}", output_file.file);
+
+        let mut problems = Vec::<String>::new();
+
+        for (pos, component) in components.iter().enumerate() {
+            if component.is_empty() && pos > 0 {
+                problems.push("empty folder name".to_owned());
+            //
+            } else if component.len() > 255 {
+                problems.push(format!(
+                    "\"{}...\" is longer than 255 bytes",
+                    component.chars().take(24).collect::<String>()
+                ));
+            //
+            } else if *component != component.trim()
+                || (component.ends_with('.') && *component != "." && *component != "..")
+            {
+                problems.push(format!(
+                    "\"{}\" starts or ends with spaces or a dot, which some systems don't allow",
+                    component
+                ));
+            }
+        }
 
-fn print_usage(program: &str, opts: getopts::Options) {
-    let brief = format!("Usage: {} [OPTIONS] URIs...", program);
-    print!("{}", opts.usage(&brief));
-}
+        for problem in &problems {
+            println!("   - {}: {}", "warning".yellow().bold(), problem);
+        }
+
+        num_warnings += problems.len();
+    }
 
-fn print_version() {
-    println!("rippify version {}\n", VERSION);
     println!(
-        "Copyright (C) 2023 Antonio de Haro. \n\
-        This program is distributed under the MIT license, see the attatched LICENSE.txt file for terms and conditions. \n\
-        This software is provided without any warranty of any kind. \n\
-        Copyright atributions for any third party code included are provided in the attatched COPYRIGHT.md file."
+        "\n{} {} errors, {} warnings",
+        "=>".green().bold(),
+        num_errors,
+        num_warnings
     );
-}
 
-enum ResourceKind {
-    Track,
-    Playlist,
-    Album,
-    Artist,
+    num_errors == 0
 }
 
-impl fmt::Display for ResourceKind {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match self {
-            ResourceKind::Track => write!(f, "track"),
-            ResourceKind::Playlist => write!(f, "playlist"),
-            ResourceKind::Album => write!(f, "album"),
-            ResourceKind::Artist => write!(f, "artist"),
+// Lists the albums with tracks missing from the library and asks whether to download them, giving their uris if so
+fn offer_album_completion(root: &str) -> Vec<String> {
+    let dirs = match manifest::AlbumManifest::find_all(path::Path::new(root)) {
+        Ok(dirs) => dirs,
+        Err(err) => {
+            println!("{}: cannot search {}: {}", "error".red().bold(), root, err);
+            proc::exit(1);
         }
-    }
-}
+    };
 
-impl ResourceKind {
-    fn to_url_regex(&self) -> regex::Regex {
-        regex::Regex::new(&format!(
-            r"^(http(s)?://)?open\.spotify\.com/{}/([[:alnum:]]{{22}})$",
-            self
-        ))
-        .unwrap()
+    let incomplete = dirs
+        .iter()
+        .filter_map(|dir| manifest::AlbumManifest::load(dir).ok().flatten())
+        .filter(|manifest| !manifest.album_id.is_empty() && manifest.tracks.len() < manifest.total_tracks)
+        .collect::<Vec<_>>();
+
+    if incomplete.is_empty() {
+        println!("{} No incomplete albums found under {}", "=>".green().bold(), root);
+        return Vec::new();
     }
 
-    fn to_uri_regex(&self) -> regex::Regex {
-        regex::Regex::new(&format!(r"^spotify:{}:([[:alnum:]]{{22}})$", self)).unwrap()
+    println!("{} Incomplete albums under {}:", "=>".green().bold(), root.bold());
+
+    for manifest in &incomplete {
+        println!(
+            " {} {} - {}{}: {} of {} tracks",
+            "->".yellow().bold(),
+            manifest.artist,
+            manifest.album.bold(),
+            manifest.year.map(|year| format!(" ({})", year)).unwrap_or_default(),
+            manifest.tracks.len(),
+            manifest.total_tracks
+        );
     }
-}
 
-struct InputResource {
-    kind: ResourceKind,
-    id: lsc::SpotifyId,
-}
+    print!(
+        "\n{} Download the {} missing tracks? [y/N] ",
+        "=>".green().bold(),
+        incomplete
+            .iter()
+            .map(|manifest| manifest.total_tracks - manifest.tracks.len())
+            .sum::<usize>()
+    );
+    io::stdout().flush().ok();
 
-impl InputResource {
-    #[async_recursion]
-    async fn get_tracks(&self, session: &lsc::Session) -> Result<Vec<lsc::SpotifyId>, librespot_core::error::Error> {
-        let mut tracks: Vec<lsc::SpotifyId> = Vec::new();
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).ok();
 
-        match self.kind {
-            ResourceKind::Track => {
-                tracks.push(self.id);
-            }
-            ResourceKind::Playlist => {
-                let playlist = lsm::Playlist::get(session, &self.id).await?;
-                tracks.extend(playlist.tracks());
-            }
-            ResourceKind::Album => {
-                let album = lsm::Album::get(session, &self.id).await?;
-                tracks.extend(album.tracks());
-            }
-            ResourceKind::Artist => {
-                let artist = lsm::Artist::get(session, &self.id).await?;
+    if !answer.trim().eq_ignore_ascii_case("y") {
+        return Vec::new();
+    }
 
-                for album_group in artist.albums.0 {
-                    for album in album_group.0 .0 {
-                        tracks.extend(
-                            InputResource {
-                                kind: ResourceKind::Album,
-                                id: album,
-                            }
-                            .get_tracks(session)
-                            .await?,
-                        );
-                    }
-                }
+    incomplete
+        .iter()
+        .map(|manifest| format!("spotify:album:{}", manifest.album_id))
+        .collect()
+}
 
-                for album_group in artist.singles.0 {
-                    for album in album_group.0 .0 {
-                        tracks.extend(
-                            InputResource {
-                                kind: ResourceKind::Album,
-                                id: album,
-                            }
-                            .get_tracks(session)
-                            .await?,
-                        );
-                    }
-                }
-            }
+// Lists the tracks and albums a search finds and asks which of them to download, giving their uris
+async fn pick_search_results(
+    session: &lsc::Session,
+    metadata_cache: &metadata::MetadataCache,
+    query: &str,
+) -> Vec<String> {
+    let results = match search::search(session, query, 10).await {
+        Ok(results) => results,
+        Err(err) => {
+            println!("{}: cannot search for \"{}\": {}", "error".red().bold(), query, err);
+            proc::exit(1);
         }
+    };
 
-        Ok(tracks)
+    if results.tracks.hits.is_empty() && results.albums.hits.is_empty() {
+        println!("{} Nothing found for \"{}\"", "=>".green().bold(), query);
+        return Vec::new();
     }
-}
 
-fn get_resource_from_line(line: &str) -> Result<InputResource, &str> {
-    if let Some(id) = is_resource(line, ResourceKind::Track) {
-        Ok(InputResource {
-            kind: ResourceKind::Track,
-            id,
-        })
-    //
-    } else if let Some(id) = is_resource(line, ResourceKind::Album) {
-        Ok(InputResource {
-            kind: ResourceKind::Album,
-            id,
-        })
-    //
-    } else if let Some(id) = is_resource(line, ResourceKind::Playlist) {
-        Ok(InputResource {
-            kind: ResourceKind::Playlist,
-            id,
-        })
-    //
-    } else if let Some(id) = is_resource(line, ResourceKind::Artist) {
-        Ok(InputResource {
-            kind: ResourceKind::Artist,
-            id,
-        })
-    //
-    } else {
-        Err(line)
+    // Search hits don't come with a year, it's taken from the albums
+    let album_year = |uri: String| async move {
+        let id = lsc::SpotifyId::from_uri(&uri).ok()?;
+        let album = metadata_cache.album(session, &id).await.ok()?;
+        Some(album.date.year()).filter(|year| *year > 0)
+    };
+
+    let track_years = future::join_all(results.tracks.hits.iter().map(|hit| album_year(hit.album.uri.clone()))).await;
+    let album_years = future::join_all(results.albums.hits.iter().map(|hit| album_year(hit.uri.clone()))).await;
+
+    let artist_names = |artists: &[search::ArtistHit]| {
+        artists
+            .iter()
+            .map(|artist| artist.name.as_str())
+            .collect::<Vec<_>>()
+            .join(", ")
+    };
+
+    let mut uris = Vec::<String>::new();
+
+    if !results.tracks.hits.is_empty() {
+        println!("{} Tracks:", "=>".green().bold());
     }
-}
 
-fn is_resource(line: &str, res: ResourceKind) -> Option<lsc::SpotifyId> {
-    if let Some(captures) = res.to_url_regex().captures(line).or(res.to_uri_regex().captures(line)) {
-        let id_str = captures.iter().last().unwrap().unwrap().as_str();
-        let id = lsc::SpotifyId::from_base62(id_str).unwrap();
+    for (hit, year) in results.tracks.hits.iter().zip(track_years) {
+        uris.push(hit.uri.clone());
+
+        println!(
+            " {:>2} {} {} - {} ({}{}) [{}:{:02}]",
+            uris.len(),
+            "->".yellow().bold(),
+            artist_names(&hit.artists),
+            hit.name.bold(),
+            hit.album.name,
+            year.map(|year| format!(", {}", year)).unwrap_or_default(),
+            hit.duration / 60_000,
+            hit.duration / 1000 % 60
+        );
+    }
 
-        Some(id)
-    //
-    } else {
-        None
+    if !results.albums.hits.is_empty() {
+        println!("\n{} Albums:", "=>".green().bold());
     }
-}
 
-async fn get_track_from_id(
-    session: &lsc::Session,
-    id: &lsc::SpotifyId,
-) -> Result<(lsm::Track, lsc::FileId), librespot_core::error::Error> {
-    let mut track_ids = coll::VecDeque::<lsc::SpotifyId>::new();
-    track_ids.push_back(id.to_owned());
-
-    while let Some(id) = track_ids.pop_front() {
-        let track = lsm::Track::get(session, &id).await?;
-
-        match None
-            .or(track.files.get_key_value(&lsm_audio::AudioFileFormat::OGG_VORBIS_320))
-            .or(track.files.get_key_value(&lsm_audio::AudioFileFormat::OGG_VORBIS_160))
-            .or(track.files.get_key_value(&lsm_audio::AudioFileFormat::OGG_VORBIS_96))
-        {
-            Some(format) => return Ok((track.to_owned(), format.1.to_owned())),
-            None => track_ids.extend(track.alternatives.0),
-        };
+    for (hit, year) in results.albums.hits.iter().zip(album_years) {
+        uris.push(hit.uri.clone());
+
+        println!(
+            " {:>2} {} {} - {}{}",
+            uris.len(),
+            "->".yellow().bold(),
+            artist_names(&hit.artists),
+            hit.name.bold(),
+            year.map(|year| format!(" ({})", year)).unwrap_or_default()
+        );
     }
 
-    Err(librespot_core::error::Error::not_found("cannot find a suitable track"))
-}
+    print!(
+        "\n{} Download which? Numbers or ranges like 1 3-5, nothing to cancel: ",
+        "=>".green().bold()
+    );
+    io::stdout().flush().ok();
 
-struct OutputFormat {
-    format_string: String,
-}
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).ok();
 
-#[derive(Debug)]
-struct OutputFile {
-    dir: Option<String>,
-    file: String,
-}
+    let mut picked = Vec::<String>::new();
 
-impl OutputFormat {
-    fn parse_output_format(&self, track: &lsm::Track) -> OutputFile {
-        let parsed = self
-            .format_string
-            .replace("{author}", &track.artists.first().unwrap().name) // NOTE: using the first found artist as the "main" artist
-            .replace("{album}", &track.album.name)
-            .replace("{name}", &track.name.as_str().replace('/', " "))
-            .replace("{ext}", "ogg");
+    for choice in answer.split([' ', ',']).filter(|choice| !choice.trim().is_empty()) {
+        let choice = choice.trim();
+        let range = match choice.split_once('-') {
+            Some((first, last)) => first.parse::<usize>().ok().zip(last.parse::<usize>().ok()),
+            None => choice.parse::<usize>().ok().map(|num| (num, num)),
+        };
 
-        OutputFile {
-            dir: parsed.rfind('/').map(|split_pos| parsed[..=split_pos].to_owned()),
-            file: parsed,
+        match range {
+            Some((first, last)) if first >= 1 && first <= last && last <= uris.len() => {
+                picked.extend(uris[first - 1..last].iter().cloned())
+            }
+            _ => println!(
+                "{}: ignoring \"{}\", not one of the results",
+                "warning".yellow().bold(),
+                choice
+            ),
         }
     }
-}
 
-trait ProcessErrorKind {}
-
-struct ProcessError<T: ProcessErrorKind> {
-    kind: T,
-    error: Box<dyn std::error::Error>,
+    picked
 }
 
-enum TrackDownloadErrorKind {
-    AudioKey,
-    AudioFile,
-    TrackFile,
-    Decrypt,
-}
+fn print_library_stats(root: &str) {
+    let dirs = match manifest::AlbumManifest::find_all(path::Path::new(root)) {
+        Ok(dirs) => dirs,
+        Err(err) => {
+            println!("{}: cannot search {}: {}", "error".red().bold(), root, err);
+            proc::exit(1);
+        }
+    };
 
-impl ProcessErrorKind for TrackDownloadErrorKind {}
-type TrackDownloadError = ProcessError<TrackDownloadErrorKind>;
+    let manifests = dirs
+        .iter()
+        .filter_map(|dir| manifest::AlbumManifest::load(dir).ok().flatten())
+        .collect::<Vec<_>>();
 
-async fn track_download(
-    track: &lsm::Track,
-    file_id: &lsc::FileId,
-    session: &lsc::Session,
-) -> Result<Vec<u8>, TrackDownloadError> {
-    let track_file_key = session
-        .audio_key()
-        .request(track.id, *file_id)
-        .await
-        .map_err(|e| ProcessError {
-            kind: TrackDownloadErrorKind::AudioKey,
-            error: e.into(),
-        })?;
+    if manifests.is_empty() {
+        println!("{} No album manifests found under {}", "=>".green().bold(), root);
+        return;
+    }
 
-    let mut track_buffer = Vec::<u8>::new();
-    let mut track_buffer_decrypted = Vec::<u8>::new();
+    let mut by_artist = coll::BTreeMap::<&str, (usize, usize)>::new();
+    let mut by_year = coll::BTreeMap::<Option<i32>, usize>::new();
+    let mut by_quality = coll::BTreeMap::<&str, usize>::new();
+    // Sample rate and channels, for tracks written since they started being recorded
+    let mut by_format = coll::BTreeMap::<Option<(u32, u8)>, usize>::new();
 
-    let mut track_file_audio = lsa::AudioFile::open(session, *file_id, 40)
-        .await
-        .map_err(|e| ProcessError {
-            kind: TrackDownloadErrorKind::AudioFile,
-            error: e.into(),
-        })?;
+    let mut num_tracks: usize = 0;
+    let mut num_incomplete: usize = 0;
+    let mut total_size: u64 = 0;
+    let mut total_duration_ms: u64 = 0;
 
-    track_file_audio
-        .read_to_end(&mut track_buffer)
-        .map_err(|e| ProcessError {
-            kind: TrackDownloadErrorKind::TrackFile,
-            error: e.into(),
-        })?;
+    for manifest in &manifests {
+        let artist = by_artist.entry(&manifest.artist).or_default();
+        artist.0 += 1;
+        artist.1 += manifest.tracks.len();
 
-    lsa::AudioDecrypt::new(Some(track_file_key), &track_buffer[..])
-        .read_to_end(&mut track_buffer_decrypted)
-        .map_err(|e| ProcessError {
-            kind: TrackDownloadErrorKind::Decrypt,
-            error: e.into(),
-        })?;
+        *by_year.entry(manifest.year).or_default() += 1;
 
-    Ok(track_buffer_decrypted[0xa7..].to_vec())
-}
+        if manifest.tracks.len() < manifest.total_tracks {
+            num_incomplete += 1;
+        }
 
-enum TrackWriteErrorKind {
-    FolderCreate,
-    FileCreate,
-    FileWrite,
-}
+        for track in &manifest.tracks {
+            *by_quality.entry(&track.quality).or_default() += 1;
+            *by_format
+                .entry(track.audio.map(|audio| (audio.sample_rate, audio.channels)))
+                .or_default() += 1;
 
-impl ProcessErrorKind for TrackWriteErrorKind {}
-type TrackWriteError = ProcessError<TrackWriteErrorKind>;
+            num_tracks += 1;
+            total_size += track.size;
+            total_duration_ms += track.duration_ms.max(0) as u64;
+        }
+    }
+
+    println!("{} Library stats for {}:", "=>".green().bold(), root.bold());
+    println!(" {} {} artists", "->".yellow().bold(), by_artist.len());
+    println!(
+        " {} {} albums, {} incomplete",
+        "->".yellow().bold(),
+        manifests.len(),
+        num_incomplete
+    );
+    println!(" {} {} tracks", "->".yellow().bold(), num_tracks);
+    println!(
+        " {} {:.1} MiB",
+        "->".yellow().bold(),
+        total_size as f64 / (1024.0 * 1024.0)
+    );
+    println!(
+        " {} {}h {:02}m of audio",
+        "->".yellow().bold(),
+        total_duration_ms / 3_600_000,
+        total_duration_ms / 60_000 % 60
+    );
 
-fn track_write(track_buffer: Vec<u8>, output_file: OutputFile) -> Result<String, TrackWriteError> {
-    if let Some(path) = output_file.dir {
-        fs::create_dir_all(path).map_err(|e| TrackWriteError {
-            kind: TrackWriteErrorKind::FolderCreate,
-            error: e.into(),
-        })?;
+    println!("\n{} By quality:", "=>".green().bold());
+    for (quality, count) in &by_quality {
+        let quality = if quality.is_empty() { "unknown" } else { quality };
+        println!(" {} {}: {} tracks", "->".yellow().bold(), quality, count);
     }
 
-    let mut file_write = fs::File::create(&output_file.file).map_err(|e| ProcessError {
-        kind: TrackWriteErrorKind::FileCreate,
-        error: e.into(),
-    })?;
+    println!("\n{} By format:", "=>".green().bold());
+    for (format, count) in &by_format {
+        let format = match format {
+            Some((sample_rate, channels)) => format!("{} Hz, {} channels", sample_rate, channels),
+            None => "unknown".to_owned(),
+        };
+        println!(" {} {}: {} tracks", "->".yellow().bold(), format, count);
+    }
 
-    io::copy(&mut track_buffer.as_slice(), &mut file_write).map_err(|e| ProcessError {
-        kind: TrackWriteErrorKind::FileWrite,
-        error: e.into(),
-    })?;
+    println!("\n{} By year:", "=>".green().bold());
+    for (year, count) in &by_year {
+        let year = year.map(|year| year.to_string()).unwrap_or("unknown".to_owned());
+        println!(" {} {}: {} albums", "->".yellow().bold(), year, count);
+    }
 
-    Ok(output_file.file)
+    let mut by_artist = by_artist.into_iter().collect::<Vec<_>>();
+    by_artist.sort_by_key(|(_, (_, tracks))| cmp::Reverse(*tracks));
+
+    println!("\n{} By artist:", "=>".green().bold());
+    for (artist, (albums, tracks)) in by_artist {
+        let artist = if artist.is_empty() { "unknown" } else { artist };
+        println!(
+            " {} {}: {} albums, {} tracks",
+            "->".yellow().bold(),
+            artist,
+            albums,
+            tracks
+        );
+    }
 }
 
-fn track_add_metadata_tags(track_buffer: Vec<u8>, track: &lsm::Track) -> Result<Vec<u8>, TagsWriteError> {
-    let mut metadata = lhr::CommentHeader {
-        vendor: String::from("Ogg"),
-        comment_list: Vec::new(),
+fn write_dir_playlist(root: &str, output: Option<&str>, paths: manifest::PlaylistPaths) {
+    let output = match output {
+        Some(output) => path::PathBuf::from(output),
+        None => path::Path::new(root).join("playlist.m3u8"),
     };
 
-    metadata.comment_list.push((String::from("title"), track.name.clone()));
-    metadata
-        .comment_list
-        .push((String::from("album"), track.album.name.clone()));
-
-    metadata.comment_list.extend(
-        track
-            .artists
-            .iter()
-            .map(|artist| (String::from("artist"), artist.name.clone()))
-            .collect::<Vec<_>>(),
-    );
+    let format = match output.extension().and_then(|ext| ext.to_str()) {
+        Some("xspf") => manifest::PlaylistFormat::Xspf,
+        _ => manifest::PlaylistFormat::M3u,
+    };
 
-    replace_header_comment(&track_buffer, &metadata)
+    match manifest::write_playlist(path::Path::new(root), &output, format, paths) {
+        Ok(count) => println!(
+            "{} Wrote {} tracks to \"{}\"",
+            "=>".green().bold(),
+            count.to_string().bold(),
+            output.display()
+        ),
+        Err(err) => {
+            println!(
+                "{}: cannot write playlist {}: {}",
+                "error".red().bold(),
+                output.display(),
+                err
+            );
+            proc::exit(1);
+        }
+    }
 }
 
-// Reverse implementation of https://github.com/RustAudio/lewton/blob/bb2955b717094b40260902cf2f8dd9c5ea62a84a/src/header.rs#L309
-fn make_header_comment(header: &lhr::CommentHeader) -> Option<Vec<u8>> {
-    let mut packet: Vec<u8> = vec![];
+fn verify_library(root: &str, quick: bool) -> bool {
+    let dirs = match manifest::AlbumManifest::find_all(path::Path::new(root)) {
+        Ok(dirs) => dirs,
+        Err(err) => {
+            println!("{}: cannot search {}: {}", "error".red().bold(), root, err);
+            proc::exit(1);
+        }
+    };
 
-    // 'V' 'O' 'R' 'B' 'I' 'S'
-    packet.extend([0x03, 0x76, 0x6F, 0x72, 0x62, 0x69, 0x73] as [u8; 7]);
+    if dirs.is_empty() {
+        println!("{} No album manifests found under {}", "=>".green().bold(), root);
+        return true;
+    }
 
-    let vendor_buf = header.vendor.as_bytes();
-    let vendor_len = TryInto::<u32>::try_into(vendor_buf.len()).ok()?.to_le_bytes();
+    println!(
+        "{} Verifying {} albums{}:",
+        "=>".green().bold(),
+        dirs.len().to_string().bold(),
+        if quick { " (quick)" } else { "" }
+    );
 
-    packet.extend(vendor_len);
-    packet.extend(vendor_buf);
+    let mut num_tracks: usize = 0;
+    let mut num_problems: usize = 0;
+    let mut num_incomplete: usize = 0;
 
-    let comments_len = TryInto::<u32>::try_into(header.comment_list.len()).ok()?.to_le_bytes();
+    for dir in dirs {
+        let manifest = match manifest::AlbumManifest::load(&dir) {
+            Ok(Some(manifest)) => manifest,
+            Ok(None) => continue,
+            Err(err) => {
+                println!(
+                    " {} {}: {}: cannot read album.json: {}",
+                    "->".yellow().bold(),
+                    dir.display(),
+                    "warning".yellow().bold(),
+                    err
+                );
+                num_problems += 1;
+                continue;
+            }
+        };
 
-    packet.extend(comments_len);
+        println!(
+            " {} {} ({} of {} tracks)",
+            "->".yellow().bold(),
+            manifest.album.bold(),
+            manifest.tracks.len(),
+            manifest.total_tracks
+        );
 
-    for comment in &header.comment_list {
-        let comment_buf = format!("{}={}", comment.0, comment.1);
-        let comment_buf = comment_buf.as_bytes();
-        let comment_len = TryInto::<u32>::try_into(comment_buf.len()).ok()?.to_le_bytes();
+        if manifest.tracks.len() < manifest.total_tracks {
+            num_incomplete += 1;
+        }
 
-        packet.extend(comment_len);
-        packet.extend(comment_buf);
-    }
+        for track in &manifest.tracks {
+            num_tracks += 1;
 
-    packet.extend([0x01] as [u8; 1]);
-    Some(packet)
-}
+            if let Some(problem) = manifest::AlbumManifest::check_track(&dir, track, quick) {
+                println!(
+                    "   - {}: \"{}\": {}",
+                    "warning".yellow().bold(),
+                    dir.join(&track.file).display(),
+                    problem
+                );
+                num_problems += 1;
+            }
+        }
+    }
 
-enum TagsWriteErrorKind {
-    Read,
-    Write,
-    Header,
-}
+    let orphans = manifest::AlbumManifest::find_orphans(path::Path::new(root)).unwrap_or_else(|err| {
+        println!(
+            "\n{}: cannot look for unknown files: {}",
+            "warning".yellow().bold(),
+            err
+        );
+        Vec::new()
+    });
 
-impl ProcessErrorKind for TagsWriteErrorKind {}
-type TagsWriteError = ProcessError<TagsWriteErrorKind>;
+    if !orphans.is_empty() {
+        println!("\n{} Files not in any album manifest:", "=>".green().bold());
 
-// Based on https://github.com/RustAudio/ogg/blob/0910d8d57645eccc1a1400731fefef376859c661/examples/repack.rs#L52
-fn replace_header_comment(
-    ogg_buffer: &Vec<u8>,
-    comment_header: &lhr::CommentHeader,
-) -> Result<Vec<u8>, TagsWriteError> {
-    let mut out_buffer = io::Cursor::new(Vec::<u8>::new());
-    let mut in_buffer = io::Cursor::new(ogg_buffer);
+        for orphan in &orphans {
+            println!(" {} {}", "->".yellow().bold(), orphan.display());
+        }
+    }
 
-    let mut reader = ogg::PacketReader::new(&mut in_buffer);
-    let mut writer = ogg::PacketWriter::new(&mut out_buffer);
+    println!("\n{} Verified tracks: ", "=>".green().bold());
+    println!(
+        " {} {} ok",
+        "->".yellow().bold(),
+        num_tracks.saturating_sub(num_problems)
+    );
+    println!(" {} {} with problems", "->".yellow().bold(), num_problems);
+    println!(" {} {} incomplete albums", "->".yellow().bold(), num_incomplete);
+    println!(" {} {} unknown files", "->".yellow().bold(), orphans.len());
 
-    let mut overwrote_header = false;
+    num_problems == 0
+}
 
-    loop {
-        if let Some(mut packet) = reader.read_packet().map_err(|e| TagsWriteError {
-            kind: TagsWriteErrorKind::Read,
-            error: e.into(),
-        })? {
-            if !overwrote_header {
-                if let Ok(_) = lhr::read_header_comment(&packet.data) {
-                    packet.data = make_header_comment(comment_header).ok_or(TagsWriteError {
-                        kind: TagsWriteErrorKind::Header,
-                        error: "invalid header comment data".into(),
-                    })?;
-                    overwrote_header = true;
-                }
-            }
+fn print_version() {
+    match GIT_COMMIT {
+        "" => println!("rippify version {}\n", VERSION),
+        commit => println!("rippify version {} ({})\n", VERSION, commit),
+    }
 
-            let packet_inf = if packet.last_in_stream() {
-                ogg::PacketWriteEndInfo::EndStream
-            } else if packet.last_in_page() {
-                ogg::PacketWriteEndInfo::EndPage
-            } else {
-                ogg::PacketWriteEndInfo::NormalPacket
-            };
+    println!(
+        "Copyright (C) 2023 Antonio de Haro. \n\
+        This program is distributed under the MIT license, see the attatched LICENSE.txt file for terms and conditions. \n\
+        This software is provided without any warranty of any kind. \n\
+        Copyright atributions for any third party code included are provided in the attatched COPYRIGHT.md file."
+    );
+}
 
-            let packet_serial = packet.stream_serial();
-            let packet_absgp = packet.absgp_page();
+fn print_version_json() {
+    let info = serde_json::json!({
+        "name": "rippify",
+        "version": VERSION,
+        "git_commit": Some(GIT_COMMIT).filter(|commit| !commit.is_empty()),
+        "target": env!("RIPPIFY_TARGET"),
+        // Nothing is left out at build time, these depend on what's installed and on what rippify has at all
+        "features": {
+            "transcode": {
+                "encodings": transcode::Encoding::ALL.iter().map(|encoding| encoding.to_string()).collect::<Vec<_>>(),
+                "ffmpeg": transcode::ffmpeg_available(),
+            },
+            "server_mode": false,
+        },
+        "librespot": {
+            "version": lsc::version::SEMVER,
+            "git_commit": lsc::version::SHA_SHORT,
+        },
+    });
+
+    println!("{}", info);
+}
 
-            writer
-                .write_packet(packet.data, packet_serial, packet_inf, packet_absgp)
-                .map_err(|e| TagsWriteError {
-                    kind: TagsWriteErrorKind::Write,
-                    error: e.into(),
-                })?;
-        } else {
-            break;
-        }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_take_a_unit() {
+        assert_eq!(parse_duration("30"), Some(time::Duration::from_secs(30)));
+        assert_eq!(parse_duration(" 45s "), Some(time::Duration::from_secs(45)));
+        assert_eq!(parse_duration("5m"), Some(time::Duration::from_secs(300)));
+        assert_eq!(parse_duration("12h"), Some(time::Duration::from_secs(43200)));
+        assert_eq!(parse_duration("30d"), Some(time::Duration::from_secs(2592000)));
+        assert_eq!(parse_duration("0"), None);
+        assert_eq!(parse_duration("5w"), None);
+        assert_eq!(parse_duration("m"), None);
     }
 
-    Ok(out_buffer.into_inner())
+    #[test]
+    fn duration_ranges_share_the_last_unit() {
+        let secs = time::Duration::from_secs;
+
+        assert_eq!(parse_duration_range("10s"), Some((secs(10), secs(10))));
+        assert_eq!(parse_duration_range("5..30s"), Some((secs(5), secs(30))));
+        assert_eq!(parse_duration_range("1..2m"), Some((secs(60), secs(120))));
+        assert_eq!(parse_duration_range("5..2m"), None);
+        assert_eq!(parse_duration_range("30s..2m"), Some((secs(30), secs(120))));
+        assert_eq!(parse_duration_range("30..5"), None);
+        assert_eq!(parse_duration_range("..5"), None);
+    }
 }
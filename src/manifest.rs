@@ -0,0 +1,562 @@
+use serde::{Deserialize, Serialize};
+use sha2::Digest;
+use std::collections as coll;
+use std::fs;
+use std::io;
+use std::path;
+
+#[derive(Serialize, Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct ManifestTrack {
+    pub id: String,
+    pub disc_number: i32,
+    pub number: i32,
+    pub name: String,
+    pub duration_ms: i32,
+    pub file: String,
+    pub quality: String,
+    pub size: u64,
+    pub sha256: String,
+    // Checksum of the decrypted audio before tagging, the same for every release of a recording
+    pub audio_sha256: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audio: Option<AudioInfo>,
+    // The id the track was asked for under, when an alternative was downloaded in its place
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub requested_id: Option<String>,
+    // The radio the track was recommended by, when it came from --radio
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub radio: Option<RadioSeed>,
+}
+
+// What a radio was asked for: the track or artist it was seeded from and how many recommendations
+#[derive(Serialize, Deserialize, Clone)]
+pub struct RadioSeed {
+    pub seed: String,
+    pub count: usize,
+}
+
+// From the vorbis identification header, for tracks written as ogg
+#[derive(Serialize, Deserialize, Clone, Copy)]
+pub struct AudioInfo {
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub nominal_bitrate: i32,
+}
+
+// Kept as album.json next to the tracks of an album, listing what has been ripped into that folder so far
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct AlbumManifest {
+    pub album: String,
+    pub album_id: String,
+    pub artist: String,
+    pub year: Option<i32>,
+    pub total_tracks: usize,
+    pub tracks: Vec<ManifestTrack>,
+}
+
+impl AlbumManifest {
+    pub fn path(dir: &path::Path) -> path::PathBuf {
+        dir.join("album.json")
+    }
+
+    pub fn load(dir: &path::Path) -> io::Result<Option<AlbumManifest>> {
+        let path = AlbumManifest::path(dir);
+
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let file = fs::File::open(path)?;
+        Ok(Some(serde_json::from_reader(io::BufReader::new(file))?))
+    }
+
+    // Adds a track to the album manifest in `dir`, creating it from `album` (which has no tracks) when missing.
+    // A manifest that belongs to another album is left alone
+    pub fn record(dir: &path::Path, album: AlbumManifest, track: ManifestTrack) -> io::Result<()> {
+        let mut manifest = match AlbumManifest::load(dir)? {
+            Some(manifest) if manifest.album_id != album.album_id => {
+                return Err(io::Error::new(
+                    io::ErrorKind::AlreadyExists,
+                    format!("album.json belongs to another album ({})", manifest.album),
+                ))
+            }
+            Some(mut manifest) => {
+                manifest.artist = album.artist;
+                manifest.year = album.year;
+                manifest.total_tracks = album.total_tracks;
+                manifest
+            }
+            None => album,
+        };
+
+        manifest.tracks.retain(|existing| existing.id != track.id);
+        manifest.tracks.push(track);
+        manifest.tracks.sort_by_key(|track| (track.disc_number, track.number));
+
+        manifest.save(dir)
+    }
+
+    pub fn save(&self, dir: &path::Path) -> io::Result<()> {
+        let mut manifest = serde_json::to_vec_pretty(self)?;
+        manifest.push(b'\n');
+
+        let tmp_path = dir.join("album.json.tmp");
+        fs::write(&tmp_path, manifest)?;
+        fs::rename(tmp_path, AlbumManifest::path(dir))
+    }
+}
+
+// Media servers skip folders with one of these in them. Folders below the root with a .nomedia are skipped here too
+const IGNORE_MARKERS: [(&str, &str); 2] = [(".nomedia", ""), (".plexignore", "*\n")];
+
+pub fn mark_ignored(dir: &path::Path) -> io::Result<()> {
+    for (name, contents) in IGNORE_MARKERS {
+        let marker = dir.join(name);
+
+        if !marker.exists() {
+            fs::write(marker, contents)?;
+        }
+    }
+
+    Ok(())
+}
+
+// State, covers, playlists and ignore markers, and what interrupted writes leave behind
+fn is_own_file(name: &str) -> bool {
+    const OWN_FILES: [&str; 6] = [
+        "album.json",
+        "cover.jpg",
+        ".rippify-sync.json",
+        ".rippify-artists.json",
+        ".nomedia",
+        ".plexignore",
+    ];
+    const OWN_EXTENSIONS: [&str; 5] = [".part", ".tmp", ".m3u8", ".m3u", ".xspf"];
+
+    OWN_FILES.contains(&name) || OWN_EXTENSIONS.iter().any(|ext| name.ends_with(ext))
+}
+
+// Lyrics files are named after their track, with only the extension changed
+fn dir_has_track(dir: &path::Path, lrc_name: &str) -> bool {
+    let stem = path::Path::new(lrc_name).file_stem().unwrap_or_default().to_owned();
+
+    fs::read_dir(dir).is_ok_and(|entries| {
+        entries.filter_map(|entry| entry.ok()).any(|entry| {
+            let file = entry.path();
+            file.file_stem() == Some(&stem) && file.extension().is_some_and(|ext| ext != "lrc")
+        })
+    })
+}
+
+// The tracks an m3u playlist lists, relative ones taken from the playlist's folder
+fn playlist_files(playlist: &path::Path) -> Vec<path::PathBuf> {
+    let base = playlist.parent().unwrap_or(path::Path::new("."));
+
+    fs::read_to_string(playlist)
+        .unwrap_or_default()
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| fs::canonicalize(base.join(line)).ok())
+        .collect()
+}
+
+pub(crate) fn walk_dirs(root: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+    let mut dirs = Vec::<path::PathBuf>::new();
+    let mut pending = vec![root.to_owned()];
+
+    while let Some(dir) = pending.pop() {
+        for entry in fs::read_dir(&dir)?.filter_map(|entry| entry.ok()) {
+            if entry.file_type().is_ok_and(|kind| kind.is_dir()) && !entry.path().join(".nomedia").exists() {
+                pending.push(entry.path());
+            }
+        }
+
+        dirs.push(dir);
+    }
+
+    dirs.sort();
+    Ok(dirs)
+}
+
+pub fn sha256(buffer: &[u8]) -> String {
+    format!("{:x}", sha2::Sha256::digest(buffer))
+}
+
+// Checksums what goes through them, for data that is streamed and never held in memory all at once
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: sha2::Sha256,
+}
+
+impl<R: io::Read> HashingReader<R> {
+    pub fn new(inner: R) -> HashingReader<R> {
+        HashingReader {
+            inner,
+            hasher: sha2::Sha256::new(),
+        }
+    }
+
+    pub fn finish(self) -> String {
+        format!("{:x}", self.hasher.finalize())
+    }
+}
+
+impl<R: io::Read> io::Read for HashingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.inner.read(buf)?;
+        self.hasher.update(&buf[..len]);
+        Ok(len)
+    }
+}
+
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: sha2::Sha256,
+    len: u64,
+}
+
+impl<W: io::Write> HashingWriter<W> {
+    pub fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hasher: sha2::Sha256::new(),
+            len: 0,
+        }
+    }
+
+    // Gives back the writer along with the checksum and size of what was written
+    pub fn finish(self) -> (W, String, u64) {
+        (self.inner, format!("{:x}", self.hasher.finalize()), self.len)
+    }
+}
+
+impl<W: io::Write> io::Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.inner.write(buf)?;
+        self.hasher.update(&buf[..len]);
+        self.len += len as u64;
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+pub enum TrackProblem {
+    Missing,
+    Size(u64, u64),
+    Hash,
+    Decode(String),
+    Unreadable(String),
+}
+
+impl std::fmt::Display for TrackProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            TrackProblem::Missing => write!(f, "file is missing"),
+            TrackProblem::Size(size, expected) => write!(f, "file is {} bytes, expected {}", size, expected),
+            TrackProblem::Hash => write!(f, "checksum does not match"),
+            TrackProblem::Decode(err) => write!(f, "audio does not decode: {}", err),
+            TrackProblem::Unreadable(err) => write!(f, "cannot read file: {}", err),
+        }
+    }
+}
+
+impl AlbumManifest {
+    // Every album folder under `root`, found by the manifests in them
+    pub fn find_all(root: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        Ok(walk_dirs(root)?
+            .into_iter()
+            .filter(|dir| AlbumManifest::path(dir).exists())
+            .collect())
+    }
+
+    pub fn audio_index(root: &path::Path) -> coll::HashMap<String, path::PathBuf> {
+        let mut index = coll::HashMap::<String, path::PathBuf>::new();
+
+        for dir in AlbumManifest::find_all(root).unwrap_or_default() {
+            if let Ok(Some(manifest)) = AlbumManifest::load(&dir) {
+                for track in manifest
+                    .tracks
+                    .into_iter()
+                    .filter(|track| !track.audio_sha256.is_empty())
+                {
+                    index.insert(track.audio_sha256, dir.join(track.file));
+                }
+            }
+        }
+
+        index
+    }
+
+    // Files under `root` that no album manifest or m3u playlist lists, like strays from other tools or failed renames.
+    // rippify's own files aren't counted, lyrics only while their track is still there
+    pub fn find_orphans(root: &path::Path) -> io::Result<Vec<path::PathBuf>> {
+        let mut orphans = Vec::<path::PathBuf>::new();
+        let dirs = walk_dirs(root)?;
+
+        let mut listed = coll::HashSet::<path::PathBuf>::new();
+
+        for dir in &dirs {
+            for entry in fs::read_dir(dir)?.filter_map(|entry| entry.ok()) {
+                if entry
+                    .path()
+                    .extension()
+                    .is_some_and(|ext| ext == "m3u8" || ext == "m3u")
+                {
+                    listed.extend(playlist_files(&entry.path()));
+                }
+            }
+        }
+
+        for dir in &dirs {
+            let known = match AlbumManifest::load(dir) {
+                Ok(Some(manifest)) => manifest.tracks.into_iter().map(|track| track.file).collect(),
+                _ => Vec::new(),
+            };
+
+            for entry in fs::read_dir(dir)?.filter_map(|entry| entry.ok()) {
+                let name = entry.file_name().to_string_lossy().into_owned();
+
+                if !entry.file_type().is_ok_and(|kind| kind.is_file()) || known.contains(&name) || is_own_file(&name) {
+                    continue;
+                }
+
+                if name.ends_with(".lrc") && dir_has_track(dir, &name) {
+                    continue;
+                }
+
+                if fs::canonicalize(entry.path()).is_ok_and(|file| listed.contains(&file)) {
+                    continue;
+                }
+
+                orphans.push(entry.path());
+            }
+        }
+
+        orphans.sort();
+        Ok(orphans)
+    }
+
+    // A quick check trusts the manifest's size and checksum, otherwise ogg files are decoded all the way through too
+    pub fn check_track(dir: &path::Path, track: &ManifestTrack, quick: bool) -> Option<TrackProblem> {
+        let file = dir.join(&track.file);
+
+        let size = match fs::metadata(&file) {
+            Ok(metadata) => metadata.len(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Some(TrackProblem::Missing),
+            Err(err) => return Some(TrackProblem::Unreadable(err.to_string())),
+        };
+
+        if size != track.size {
+            return Some(TrackProblem::Size(size, track.size));
+        }
+
+        let buffer = match fs::read(&file) {
+            Ok(buffer) => buffer,
+            Err(err) => return Some(TrackProblem::Unreadable(err.to_string())),
+        };
+
+        if sha256(&buffer) != track.sha256 {
+            return Some(TrackProblem::Hash);
+        }
+
+        if quick || file.extension().is_some_and(|ext| ext != "ogg") {
+            return None;
+        }
+
+        decode_ogg(buffer)
+            .err()
+            .map(|err| TrackProblem::Decode(err.to_string()))
+    }
+}
+
+// For files without a manifest entry to compare against: ogg files have to decode and end with the end of stream
+// flag, which a file cut short by a crash doesn't have. Other files are trusted as long as they can be read
+pub fn check_file(file: &path::Path) -> Option<TrackProblem> {
+    let buffer = match fs::read(file) {
+        Ok(buffer) => buffer,
+        Err(err) => return Some(TrackProblem::Unreadable(err.to_string())),
+    };
+
+    if file.extension().is_some_and(|ext| ext != "ogg") {
+        return None;
+    }
+
+    let mut reader = ogg::PacketReader::new(io::Cursor::new(&buffer));
+    let mut ended = false;
+
+    loop {
+        match reader.read_packet() {
+            Ok(Some(packet)) => ended = packet.last_in_stream(),
+            Ok(None) => break,
+            Err(err) => return Some(TrackProblem::Decode(err.to_string())),
+        }
+    }
+
+    if !ended {
+        return Some(TrackProblem::Decode("stream ends early".to_owned()));
+    }
+
+    decode_ogg(buffer)
+        .err()
+        .map(|err| TrackProblem::Decode(err.to_string()))
+}
+
+fn decode_ogg(buffer: Vec<u8>) -> Result<(), lewton::VorbisError> {
+    let mut reader = lewton::inside_ogg::OggStreamReader::new(io::Cursor::new(buffer))?;
+    while reader.read_dec_packet()?.is_some() {}
+    Ok(())
+}
+
+pub enum PlaylistFormat {
+    M3u,
+    Xspf,
+}
+
+// Relative paths keep working when the library is mounted somewhere else, some players only take absolute ones
+#[derive(Clone, Copy)]
+pub enum PlaylistPaths {
+    Relative,
+    Absolute,
+}
+
+impl PlaylistPaths {
+    pub fn from_name(paths: &str) -> Option<PlaylistPaths> {
+        match paths {
+            "relative" => Some(PlaylistPaths::Relative),
+            "absolute" => Some(PlaylistPaths::Absolute),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct PlaylistEntry {
+    pub file: path::PathBuf,
+    pub title: String,
+    pub duration_ms: i32,
+}
+
+// Lists every track under `root` album by album, in disc and track order
+pub fn write_playlist(
+    root: &path::Path,
+    output: &path::Path,
+    format: PlaylistFormat,
+    paths: PlaylistPaths,
+) -> io::Result<usize> {
+    let root = fs::canonicalize(root)?;
+    let mut entries = Vec::<PlaylistEntry>::new();
+
+    for dir in AlbumManifest::find_all(&root)? {
+        if let Some(manifest) = AlbumManifest::load(&dir)? {
+            for track in manifest.tracks {
+                entries.push(PlaylistEntry {
+                    file: dir.join(&track.file),
+                    title: if manifest.artist.is_empty() {
+                        track.name
+                    } else {
+                        format!("{} - {}", manifest.artist, track.name)
+                    },
+                    duration_ms: track.duration_ms,
+                });
+            }
+        }
+    }
+
+    write_playlist_entries(&entries, output, format, paths)?;
+    Ok(entries.len())
+}
+
+// Relative paths are taken from the playlist's folder, going up from it for tracks kept elsewhere
+pub fn write_playlist_entries(
+    entries: &[PlaylistEntry],
+    output: &path::Path,
+    format: PlaylistFormat,
+    paths: PlaylistPaths,
+) -> io::Result<()> {
+    let base = fs::canonicalize(
+        output
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or(path::Path::new(".")),
+    )?;
+
+    let entries = entries
+        .iter()
+        .map(|entry| {
+            let file = fs::canonicalize(&entry.file)
+                .or_else(|_| path::absolute(&entry.file))
+                .unwrap_or(entry.file.clone());
+            let location = match paths {
+                PlaylistPaths::Relative => relative_path(&file, &base),
+                PlaylistPaths::Absolute => file,
+            };
+            (location.to_string_lossy().into_owned(), &entry.title, entry.duration_ms)
+        })
+        .collect::<Vec<_>>();
+
+    let mut playlist = String::new();
+
+    match format {
+        PlaylistFormat::M3u => {
+            playlist.push_str("#EXTM3U\n");
+
+            for (location, title, duration_ms) in &entries {
+                playlist.push_str(&format!("#EXTINF:{},{}\n{}\n", duration_ms / 1000, title, location));
+            }
+        }
+        PlaylistFormat::Xspf => {
+            playlist.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
+            playlist.push_str("<playlist version=\"1\" xmlns=\"http://xspf.org/ns/0/\">\n  <trackList>\n");
+
+            for (location, title, duration_ms) in &entries {
+                let location = urlencoding::encode(location).replace("%2F", "/");
+                let location = if path::Path::new(&*location).is_absolute() {
+                    format!("file://{}", location)
+                } else {
+                    location
+                };
+
+                playlist.push_str(&format!(
+                    "    <track>\n      <location>{}</location>\n      <title>{}</title>\n      <duration>{}</duration>\n    </track>\n",
+                    xml_escape(&location),
+                    xml_escape(title),
+                    duration_ms
+                ));
+            }
+
+            playlist.push_str("  </trackList>\n</playlist>\n");
+        }
+    }
+
+    fs::write(output, playlist)
+}
+
+fn relative_path(file: &path::Path, base: &path::Path) -> path::PathBuf {
+    let common = file
+        .components()
+        .zip(base.components())
+        .take_while(|(file, base)| file == base)
+        .count();
+
+    let mut relative = path::PathBuf::new();
+
+    for _ in base.components().skip(common) {
+        relative.push("..");
+    }
+
+    relative.extend(file.components().skip(common));
+    relative
+}
+
+fn xml_escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
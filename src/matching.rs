@@ -0,0 +1,168 @@
+use crate::search;
+use librespot_core as lsc;
+use std::fs;
+
+// Search results scoring below this are not trusted unless asked otherwise
+pub const MIN_CONFIDENCE: f64 = 0.75;
+
+pub struct CsvMatchParams {
+    pub file: String,
+    pub columns: Vec<String>,
+    pub threshold: f64,
+    pub review: String,
+}
+
+pub struct CsvRow {
+    pub line: u64,
+    pub artist: String,
+    pub title: String,
+    pub album: Option<String>,
+}
+
+pub struct RowMatch {
+    pub hit: search::TrackHit,
+    pub confidence: f64,
+}
+
+impl CsvRow {
+    pub fn query(&self) -> String {
+        format!("{} {}", self.artist, self.title)
+    }
+
+    pub async fn find_match(&self, session: &lsc::Session) -> Result<Option<RowMatch>, lsc::Error> {
+        let results = search::search(session, &self.query(), 5).await?;
+
+        Ok(results
+            .tracks
+            .hits
+            .into_iter()
+            .map(|hit| RowMatch {
+                confidence: self.confidence(&hit),
+                hit,
+            })
+            .max_by(|a, b| a.confidence.total_cmp(&b.confidence)))
+    }
+
+    fn confidence(&self, hit: &search::TrackHit) -> f64 {
+        track_confidence(&self.artist, &self.title, self.album.as_deref(), hit)
+    }
+}
+
+// Weighted similarity of title, artist and (when present) album against a search hit, from 0.0 to 1.0
+pub fn track_confidence(artist: &str, title: &str, album: Option<&str>, hit: &search::TrackHit) -> f64 {
+    let title = similarity(title, &hit.name);
+    let artist = artist_similarity(artist, &hit.artists);
+
+    match album {
+        Some(album) => 0.5 * title + 0.35 * artist + 0.15 * similarity(album, &hit.album.name),
+        None => (0.5 * title + 0.35 * artist) / 0.85,
+    }
+}
+
+pub fn album_confidence(artist: &str, title: &str, hit: &search::AlbumHit) -> f64 {
+    (0.5 * similarity(title, &hit.name) + 0.35 * artist_similarity(artist, &hit.artists)) / 0.85
+}
+
+// The artist may be given as any one of the hit's artists or as all of them joined
+fn artist_similarity(artist: &str, hit_artists: &[search::ArtistHit]) -> f64 {
+    let joined_artists = hit_artists
+        .iter()
+        .map(|artist| artist.name.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    hit_artists
+        .iter()
+        .map(|hit_artist| similarity(artist, &hit_artist.name))
+        .fold(similarity(artist, &joined_artists), f64::max)
+}
+
+pub fn normalize(text: &str) -> String {
+    text.to_lowercase()
+        .chars()
+        .filter(|c| c.is_alphanumeric() || c.is_whitespace())
+        .collect::<String>()
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+fn similarity(a: &str, b: &str) -> f64 {
+    strsim::normalized_levenshtein(&normalize(a), &normalize(b))
+}
+
+// A column is referenced either by its header name (case insensitive) or by its zero based index
+fn find_column(headers: &csv::StringRecord, column: &str) -> Option<usize> {
+    headers
+        .iter()
+        .position(|header| header.trim().eq_ignore_ascii_case(column.trim()))
+        .or(column
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .filter(|index| *index < headers.len()))
+}
+
+pub fn read_rows(params: &CsvMatchParams) -> Result<Vec<CsvRow>, csv::Error> {
+    let mut reader = csv::ReaderBuilder::new().flexible(true).from_path(&params.file)?;
+    let headers = reader.headers()?.clone();
+
+    let artist_col = find_column(&headers, &params.columns[0]).ok_or(csv::Error::from(io_error(format!(
+        "artist column \"{}\" not found",
+        params.columns[0]
+    ))))?;
+
+    let title_col = find_column(&headers, &params.columns[1]).ok_or(csv::Error::from(io_error(format!(
+        "title column \"{}\" not found",
+        params.columns[1]
+    ))))?;
+
+    let album_col = params.columns.get(2).and_then(|column| find_column(&headers, column));
+
+    let mut rows = Vec::<CsvRow>::new();
+
+    for record in reader.records() {
+        let record = record?;
+        let field = |col: usize| record.get(col).unwrap_or_default().trim().to_owned();
+
+        rows.push(CsvRow {
+            line: record.position().map(|pos| pos.line()).unwrap_or_default(),
+            artist: field(artist_col),
+            title: field(title_col),
+            album: album_col.map(field).filter(|album| !album.is_empty()),
+        });
+    }
+
+    Ok(rows)
+}
+
+fn io_error(message: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message)
+}
+
+pub struct ReviewWriter {
+    writer: csv::Writer<fs::File>,
+}
+
+impl ReviewWriter {
+    pub fn create(path: &str) -> Result<ReviewWriter, csv::Error> {
+        let mut writer = csv::Writer::from_path(path)?;
+        writer.write_record(["line", "artist", "title", "album", "reason", "candidate", "confidence"])?;
+
+        Ok(ReviewWriter { writer })
+    }
+
+    pub fn reject(&mut self, row: &CsvRow, reason: &str, candidate: Option<&RowMatch>) -> Result<(), csv::Error> {
+        self.writer.write_record([
+            row.line.to_string().as_str(),
+            &row.artist,
+            &row.title,
+            row.album.as_deref().unwrap_or_default(),
+            reason,
+            candidate.map(|m| m.hit.uri.as_str()).unwrap_or_default(),
+            &candidate.map(|m| format!("{:.2}", m.confidence)).unwrap_or_default(),
+        ])?;
+
+        self.writer.flush().map_err(csv::Error::from)
+    }
+}
@@ -0,0 +1,214 @@
+use async_trait::async_trait;
+use librespot_core as lsc;
+use librespot_metadata as lsm;
+use librespot_protocol as lsp;
+use lsm::Metadata;
+use lsp::extended_metadata::{BatchedEntityRequest, BatchedExtensionResponse, EntityRequest, ExtensionQuery};
+use lsp::extension_kind::ExtensionKind;
+use protobuf::Message;
+use std::collections as coll;
+use std::sync;
+
+// Where track, album, artist and playlist metadata comes from. Embedders can put their own in place of the one asking
+// Spotify, to serve it from their own cache or from fixtures, or to enrich it. The session is the one to use for
+// requests, providers that don't make any can ignore it
+#[async_trait]
+pub trait MetadataProvider: Send + Sync {
+    async fn track(&self, session: &lsc::Session, id: &lsc::SpotifyId) -> Result<lsm::Track, lsc::Error>;
+    async fn album(&self, session: &lsc::Session, id: &lsc::SpotifyId) -> Result<lsm::Album, lsc::Error>;
+    async fn artist(&self, session: &lsc::Session, id: &lsc::SpotifyId) -> Result<lsm::Artist, lsc::Error>;
+    async fn playlist(&self, session: &lsc::Session, id: &lsc::SpotifyId) -> Result<lsm::Playlist, lsc::Error>;
+
+    // Many tracks in one request. Tracks left out of the answer are asked for one at a time when they're needed, which
+    // is all of them for providers that can't do this
+    async fn tracks(&self, _session: &lsc::Session, _ids: &[lsc::SpotifyId]) -> Result<Vec<lsm::Track>, lsc::Error> {
+        Ok(Vec::new())
+    }
+}
+
+pub struct LibrespotMetadata;
+
+#[async_trait]
+impl MetadataProvider for LibrespotMetadata {
+    async fn track(&self, session: &lsc::Session, id: &lsc::SpotifyId) -> Result<lsm::Track, lsc::Error> {
+        lsm::Track::get(session, id).await
+    }
+
+    async fn album(&self, session: &lsc::Session, id: &lsc::SpotifyId) -> Result<lsm::Album, lsc::Error> {
+        lsm::Album::get(session, id).await
+    }
+
+    async fn artist(&self, session: &lsc::Session, id: &lsc::SpotifyId) -> Result<lsm::Artist, lsc::Error> {
+        lsm::Artist::get(session, id).await
+    }
+
+    async fn playlist(&self, session: &lsc::Session, id: &lsc::SpotifyId) -> Result<lsm::Playlist, lsc::Error> {
+        lsm::Playlist::get(session, id).await
+    }
+
+    // The extended metadata endpoint answers for a whole batch of entities, with the same track messages the track
+    // endpoint gives one by one
+    async fn tracks(&self, session: &lsc::Session, ids: &[lsc::SpotifyId]) -> Result<Vec<lsm::Track>, lsc::Error> {
+        let mut request = BatchedEntityRequest::new();
+
+        for id in ids {
+            let mut query = ExtensionQuery::new();
+            query.extension_kind = ExtensionKind::TRACK_V4.into();
+
+            let mut entity = EntityRequest::new();
+            entity.entity_uri = id.to_uri()?;
+            entity.query.push(query);
+
+            request.entity_request.push(entity);
+        }
+
+        let response = session.spclient().get_extended_metadata(request).await?;
+        let response = BatchedExtensionResponse::parse_from_bytes(&response)?;
+
+        let mut tracks = Vec::<lsm::Track>::new();
+
+        for data in response
+            .extended_metadata
+            .iter()
+            .flat_map(|array| array.extension_data.iter())
+        {
+            if data.header.as_ref().is_some_and(|header| header.status_code != 200) {
+                continue;
+            }
+
+            let (Ok(id), Some(extension)) = (lsc::SpotifyId::from_uri(&data.entity_uri), data.extension_data.as_ref())
+            else {
+                continue;
+            };
+
+            let message = lsp::metadata::Track::parse_from_bytes(&extension.value)?;
+            tracks.push(lsm::Track::parse(&message, &id)?);
+        }
+
+        Ok(tracks)
+    }
+}
+
+// Albums are needed both when resolving inputs and when naming their tracks, so they are kept for the whole run
+// instead of being requested again for every track. Tracks of resolved inputs are fetched ahead in batches and kept
+// too. Tracks downloading at the same time share it. Everything else is passed through to the provider as is
+pub struct MetadataCache {
+    provider: Box<dyn MetadataProvider>,
+    albums: sync::Mutex<coll::HashMap<lsc::SpotifyId, lsm::Album>>,
+    tracks: sync::Mutex<coll::HashMap<lsc::SpotifyId, lsm::Track>>,
+}
+
+// About what a client asks for at once, an album's worth of tracks in a single request
+const TRACK_BATCH_SIZE: usize = 100;
+
+impl Default for MetadataCache {
+    fn default() -> Self {
+        MetadataCache::with_provider(Box::new(LibrespotMetadata))
+    }
+}
+
+impl MetadataCache {
+    pub fn with_provider(provider: Box<dyn MetadataProvider>) -> MetadataCache {
+        MetadataCache {
+            provider,
+            albums: sync::Mutex::new(coll::HashMap::new()),
+            tracks: sync::Mutex::new(coll::HashMap::new()),
+        }
+    }
+
+    pub async fn track(&self, session: &lsc::Session, id: &lsc::SpotifyId) -> Result<lsm::Track, lsc::Error> {
+        if let Some(track) = self.tracks.lock().unwrap().get(id) {
+            return Ok(track.clone());
+        }
+
+        self.provider.track(session, id).await
+    }
+
+    // Only tracks that aren't kept already are asked for. A batch that fails leaves its tracks to be fetched one at a
+    // time and the rest go on, the errors are only returned to be reported
+    pub async fn prefetch_tracks(&self, session: &lsc::Session, ids: &[lsc::SpotifyId]) -> Vec<lsc::Error> {
+        let missing = {
+            let tracks = self.tracks.lock().unwrap();
+            ids.iter()
+                .filter(|id| id.item_type == lsc::spotify_id::SpotifyItemType::Track && !tracks.contains_key(id))
+                .copied()
+                .collect::<Vec<_>>()
+        };
+
+        let mut errors = Vec::<lsc::Error>::new();
+
+        for batch in missing.chunks(TRACK_BATCH_SIZE) {
+            match self.provider.tracks(session, batch).await {
+                Ok(fetched) => {
+                    let mut tracks = self.tracks.lock().unwrap();
+
+                    for track in fetched {
+                        tracks.insert(track.id, track);
+                    }
+                }
+                Err(err) => errors.push(err),
+            }
+        }
+
+        errors
+    }
+
+    pub async fn album(&self, session: &lsc::Session, id: &lsc::SpotifyId) -> Result<lsm::Album, lsc::Error> {
+        if let Some(album) = self.albums.lock().unwrap().get(id) {
+            return Ok(album.clone());
+        }
+
+        let album = self.provider.album(session, id).await?;
+        self.albums.lock().unwrap().insert(*id, album.clone());
+
+        Ok(album)
+    }
+
+    pub async fn artist(&self, session: &lsc::Session, id: &lsc::SpotifyId) -> Result<lsm::Artist, lsc::Error> {
+        self.provider.artist(session, id).await
+    }
+
+    pub async fn playlist(&self, session: &lsc::Session, id: &lsc::SpotifyId) -> Result<lsm::Playlist, lsc::Error> {
+        self.provider.playlist(session, id).await
+    }
+
+    // The album embedded in track metadata has no discs, so the full album is needed to count them
+    pub async fn album_info(&self, session: &lsc::Session, track: &lsm::Track) -> AlbumInfo {
+        self.album(session, &track.album.id)
+            .await
+            .map(|album| AlbumInfo {
+                disc_count: album.discs.len(),
+                track_count: album.discs.iter().map(|disc| disc.tracks.len()).sum(),
+                disc_track_counts: album.discs.iter().map(|disc| disc.tracks.len()).collect(),
+            })
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone)]
+pub struct AlbumInfo {
+    pub disc_count: usize,
+    pub track_count: usize,
+    pub disc_track_counts: Vec<usize>,
+}
+
+impl AlbumInfo {
+    // Tag readers expect the number of tracks on the track's own disc, the whole album is only a fallback
+    pub fn disc_track_count(&self, disc_number: i32) -> usize {
+        usize::try_from(disc_number - 1)
+            .ok()
+            .and_then(|disc| self.disc_track_counts.get(disc).copied())
+            .unwrap_or(self.track_count)
+    }
+}
+
+// Not knowing is treated as a single disc, so at worst the disc folder is left out
+impl Default for AlbumInfo {
+    fn default() -> Self {
+        AlbumInfo {
+            disc_count: 1,
+            track_count: 0,
+            disc_track_counts: Vec::new(),
+        }
+    }
+}
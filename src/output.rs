@@ -0,0 +1,720 @@
+use crate::classical::track_composers;
+use crate::error::{ProcessError, ProcessErrorKind};
+use crate::manifest;
+use crate::matching::normalize;
+use crate::sanitize::{fold_accent, Sanitizer};
+use librespot_core as lsc;
+use librespot_metadata as lsm;
+use std::collections as coll;
+use std::env;
+use std::fs;
+use std::io;
+use std::path;
+use std::sync;
+use std::time;
+
+pub struct OutputFormat {
+    pub format_string: String,
+    pub author_policy: AuthorPolicy,
+    pub artist_names: ArtistNames,
+    pub featuring: Featuring,
+    pub sanitizer: Sanitizer,
+    pub sort_names: SortNames,
+}
+
+// How the artist used for {author} is picked among the ones credited for a track
+pub enum AuthorPolicy {
+    First,
+    AlbumArtist,
+    JoinAll,
+    PrimaryByRole,
+}
+
+impl AuthorPolicy {
+    pub fn from_name(policy: &str) -> Option<AuthorPolicy> {
+        match policy {
+            "first" => Some(AuthorPolicy::First),
+            "album-artist" => Some(AuthorPolicy::AlbumArtist),
+            "join-all" => Some(AuthorPolicy::JoinAll),
+            "primary-by-role" => Some(AuthorPolicy::PrimaryByRole),
+            _ => None,
+        }
+    }
+
+    pub fn author(&self, track: &lsm::Track, artist_names: &ArtistNames) -> String {
+        let artists = track
+            .artists
+            .iter()
+            .map(|artist| artist_names.name(Some(&artist.id), &artist.name))
+            .collect::<Vec<_>>();
+
+        self.pick(
+            &artists.iter().map(|artist| artist.as_str()).collect::<Vec<_>>(),
+            track
+                .album
+                .artists
+                .first()
+                .map(|artist| artist_names.name(Some(&artist.id), &artist.name))
+                .as_deref(),
+            track
+                .artists_with_role
+                .iter()
+                .find(|artist| artist.role == lsm::artist::ArtistRole::ARTIST_ROLE_MAIN_ARTIST)
+                .map(|artist| artist_names.name(Some(&artist.id), &artist.name))
+                .as_deref(),
+        )
+    }
+
+    pub fn pick(&self, artists: &[&str], album_artist: Option<&str>, main_artist: Option<&str>) -> String {
+        let first = artists.first().copied().unwrap_or_default();
+
+        match self {
+            AuthorPolicy::First => first.to_owned(),
+            AuthorPolicy::AlbumArtist => album_artist.unwrap_or(first).to_owned(),
+            AuthorPolicy::JoinAll => artists.join(", "),
+            // Not every track has roles filled in, in which case there is nothing better than the first artist
+            AuthorPolicy::PrimaryByRole => main_artist.unwrap_or(first).to_owned(),
+        }
+    }
+}
+
+// Names the way players sort them, for {author_sort} and {album_sort}. Names in another script are only given a latin
+// one when the config has it, there's no transliteration table to make one up from. Any other name gets a leading
+// article moved to the end: "The Beatles" becomes "Beatles, The"
+pub struct SortNames {
+    articles: Vec<String>,
+    names: coll::HashMap<String, String>,
+}
+
+impl SortNames {
+    pub fn new(articles: Vec<String>, names: coll::HashMap<String, String>) -> SortNames {
+        SortNames {
+            articles,
+            names: names
+                .into_iter()
+                .map(|(name, sort_name)| (fold_name(&name), sort_name))
+                .collect(),
+        }
+    }
+
+    pub fn sort_name(&self, name: &str) -> String {
+        match self.names.get(&fold_name(name)) {
+            Some(sort_name) => sort_name.clone(),
+            None => move_article(name, &self.articles),
+        }
+    }
+}
+
+// Articles ending in an apostrophe, like l', don't need a space after them
+fn move_article(name: &str, articles: &[String]) -> String {
+    for article in articles {
+        let (Some(head), Some(rest)) = (name.get(..article.len()), name.get(article.len()..)) else {
+            continue;
+        };
+
+        if head.to_lowercase() != article.to_lowercase() {
+            continue;
+        }
+
+        let rest = if article.ends_with('\'') {
+            Some(rest)
+        } else {
+            rest.strip_prefix(' ')
+        };
+
+        if let Some(rest) = rest.map(|rest| rest.trim_start()).filter(|rest| !rest.is_empty()) {
+            return format!("{}, {}", rest, head);
+        }
+    }
+
+    name.to_owned()
+}
+
+// What is done with a "(feat. X)" credit in a track name. Spotify writes it a few different ways, so normalizing
+// rewrites all of them as "(feat. X)"
+pub enum Featuring {
+    Keep,
+    Strip,
+    Normalize,
+}
+
+impl Featuring {
+    pub fn from_name(mode: &str) -> Option<Featuring> {
+        match mode {
+            "keep" => Some(Featuring::Keep),
+            "strip" => Some(Featuring::Strip),
+            "normalize" => Some(Featuring::Normalize),
+            _ => None,
+        }
+    }
+
+    pub fn apply(&self, name: &str) -> String {
+        if let Featuring::Keep = self {
+            return name.to_owned();
+        }
+
+        let credit_regex = regex::Regex::new(
+            r"(?i)\s*(?:[(\[]\s*(?:feat\.?|ft\.?|featuring|with)\s+([^)\]]+)[)\]]|\s-?\s*(?:feat\.|ft\.|featuring)\s+(.+)$)",
+        )
+        .unwrap();
+
+        let Some(captures) = credit_regex.captures(name) else {
+            return name.to_owned();
+        };
+
+        let credit = captures.get(0).unwrap();
+        let artists = captures.get(1).or(captures.get(2)).unwrap().as_str().trim();
+
+        // The credit stays where it was, anything that came after it too
+        let replacement = match self {
+            Featuring::Normalize => format!(" (feat. {})", artists),
+            _ => String::new(),
+        };
+
+        format!("{}{}{}", &name[..credit.start()], replacement, &name[credit.end()..])
+    }
+}
+
+// Names artists are filed under instead of the ones they are credited with, so one artist doesn't end up spread over
+// several folders. Aliases are keyed by artist id, or by a name matched regardless of case, accents and punctuation
+#[derive(Default)]
+pub struct ArtistNames {
+    aliases: coll::HashMap<String, String>,
+    canonical: Option<CanonicalArtists>,
+}
+
+// The name an artist id was first filed under in a library, kept in the library root so later runs reuse it even
+// when the artist gets credited differently
+struct CanonicalArtists {
+    path: path::PathBuf,
+    names: sync::Mutex<coll::HashMap<String, String>>,
+}
+
+impl ArtistNames {
+    pub fn new(aliases: coll::HashMap<String, String>) -> ArtistNames {
+        ArtistNames {
+            aliases: aliases
+                .into_iter()
+                .map(|(key, name)| match artist_id(&key) {
+                    Some(id) => (id, name),
+                    None => (fold_name(&key), name),
+                })
+                .collect(),
+            canonical: None,
+        }
+    }
+
+    pub fn with_canonical(mut self, root: &str) -> io::Result<ArtistNames> {
+        let path = path::Path::new(root).join(".rippify-artists.json");
+
+        let names = match fs::read_to_string(&path) {
+            Ok(contents) => {
+                serde_json::from_str(&contents).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => coll::HashMap::new(),
+            Err(err) => return Err(err),
+        };
+
+        self.canonical = Some(CanonicalArtists {
+            path,
+            names: sync::Mutex::new(names),
+        });
+
+        Ok(self)
+    }
+
+    pub fn name(&self, id: Option<&lsc::SpotifyId>, name: &str) -> String {
+        let id = id.and_then(|id| id.to_base62().ok());
+
+        if let Some(alias) = id.as_ref().and_then(|id| self.aliases.get(id)) {
+            return alias.clone();
+        }
+
+        if let Some(alias) = self.aliases.get(&fold_name(name)) {
+            return alias.clone();
+        }
+
+        match (&self.canonical, id) {
+            (Some(canonical), Some(id)) => canonical
+                .names
+                .lock()
+                .unwrap()
+                .entry(id)
+                .or_insert_with(|| name.to_owned())
+                .clone(),
+            _ => name.to_owned(),
+        }
+    }
+
+    // Nothing is written without canonical names, or when the library root doesn't exist yet
+    pub fn save(&self) -> io::Result<()> {
+        let Some(canonical) = &self.canonical else {
+            return Ok(());
+        };
+
+        if !canonical.path.parent().is_some_and(|root| root.exists()) {
+            return Ok(());
+        }
+
+        let names = canonical.names.lock().unwrap();
+        fs::write(&canonical.path, serde_json::to_string_pretty(&*names)?)
+    }
+}
+
+// Either a bare base62 id or a spotify:artist: uri
+fn artist_id(key: &str) -> Option<String> {
+    let id = key.strip_prefix("spotify:artist:").unwrap_or(key);
+    (id.len() == 22 && id.chars().all(|c| c.is_ascii_alphanumeric())).then(|| id.to_owned())
+}
+
+fn fold_name(name: &str) -> String {
+    normalize(name).chars().map(fold_accent).collect()
+}
+
+#[derive(Debug, Clone)]
+pub struct OutputFile {
+    pub root: String,
+    pub dir: Option<String>,
+    pub file: String,
+}
+
+impl OutputFile {
+    // The same file with " (n)" before its extension
+    pub fn numbered(&self, number: usize) -> OutputFile {
+        let name_start = self.dir.as_ref().map_or(0, |dir| dir.len());
+        let stem_end = self.file[name_start..]
+            .rfind('.')
+            .map_or(self.file.len(), |pos| name_start + pos);
+
+        OutputFile {
+            root: self.root.clone(),
+            dir: self.dir.clone(),
+            file: format!("{} ({}){}", &self.file[..stem_end], number, &self.file[stem_end..]),
+        }
+    }
+}
+
+pub const FORMAT_FIELDS: [&str; 16] = [
+    "author",
+    "author_sort",
+    "artists",
+    "album_artist",
+    "composer",
+    "album",
+    "album_sort",
+    "name",
+    "ext",
+    "disc_number",
+    "disc",
+    "track_number",
+    "track_num",
+    "year",
+    "id",
+    "bitrate",
+];
+
+pub const FORMAT_MODIFIERS: [&str; 4] = ["lower", "upper", "slug", "<width>"];
+
+// Everything a track contributes to its output path, apart from the track itself so templates can be tried on
+// made up tracks too
+pub struct TrackFields {
+    pub author: String,
+    pub artists: Vec<String>,
+    pub album_artist: String,
+    pub composers: Vec<String>,
+    pub album: String,
+    pub name: String,
+    pub disc_number: i32,
+    pub disc_count: usize,
+    pub track_number: i32,
+    pub year: i32,
+    pub id: String,
+    pub bitrate: Option<u32>,
+}
+
+// A format field's value, and whether it counts as present for conditional segments
+struct FormatField {
+    name: &'static str,
+    value: String,
+    present: bool,
+}
+
+impl OutputFormat {
+    pub fn uses_field(&self, name: &str) -> bool {
+        format_uses_field(&self.format_string, name)
+    }
+
+    // The fixed part of the output path, under which the whole library is kept
+    pub fn root(&self) -> String {
+        let prefix = &self.format_string[..self.format_string.find('{').unwrap_or(0)];
+
+        match prefix.rfind('/') {
+            Some(split_pos) => prefix[..=split_pos].to_owned(),
+            None => ".".to_owned(),
+        }
+    }
+
+    // Whether every album gets a folder of its own, which is where album manifests go
+    pub fn has_album_dirs(&self) -> bool {
+        format_has_album_dirs(&self.format_string)
+    }
+
+    pub fn parse_output_format(
+        &self,
+        track: &lsm::Track,
+        disc_count: usize,
+        bitrate: Option<u32>,
+        ext: &str,
+    ) -> OutputFile {
+        let fields = TrackFields {
+            author: self.author_policy.author(track, &self.artist_names),
+            artists: track
+                .artists
+                .iter()
+                .map(|artist| self.artist_names.name(Some(&artist.id), &artist.name))
+                .collect(),
+            album_artist: track
+                .album
+                .artists
+                .first()
+                .map(|artist| self.artist_names.name(Some(&artist.id), &artist.name))
+                .unwrap_or_default(),
+            composers: track_composers(track)
+                .iter()
+                .map(|composer| self.artist_names.name(Some(&composer.id), &composer.name))
+                .collect(),
+            album: track.album.name.clone(),
+            name: self.featuring.apply(&track.name),
+            disc_number: track.disc_number,
+            disc_count,
+            track_number: track.number,
+            year: track.album.date.year(),
+            id: track.id.to_base62().unwrap_or_default(),
+            bitrate,
+        };
+
+        self.expand_fields(&fields, ext)
+    }
+
+    pub fn expand_fields(&self, track: &TrackFields, ext: &str) -> OutputFile {
+        let text_field = |name, value: String| FormatField {
+            name,
+            present: !value.is_empty(),
+            value: self.sanitizer.sanitize(&value),
+        };
+
+        let fields = [
+            text_field("author", track.author.clone()),
+            text_field("author_sort", self.sort_names.sort_name(&track.author)),
+            text_field("artists", track.artists.join(", ")),
+            text_field("album_artist", track.album_artist.clone()),
+            text_field("composer", track.composers.join(", ")),
+            text_field("album", track.album.clone()),
+            text_field("album_sort", self.sort_names.sort_name(&track.album)),
+            text_field("name", track.name.clone()),
+            text_field("ext", ext.to_owned()),
+            text_field("id", track.id.clone()),
+            // Every track has a disc number, but it only means something when the album has more than one disc
+            FormatField {
+                name: "disc_number",
+                value: track.disc_number.to_string(),
+                present: track.disc_count > 1,
+            },
+            FormatField {
+                name: "disc",
+                value: track.disc_number.to_string(),
+                present: track.disc_count > 1,
+            },
+            FormatField {
+                name: "track_number",
+                value: format!("{:02}", track.track_number),
+                present: track.track_number > 0,
+            },
+            FormatField {
+                name: "track_num",
+                value: track.track_number.to_string(),
+                present: track.track_number > 0,
+            },
+            FormatField {
+                name: "year",
+                value: track.year.to_string(),
+                present: track.year > 0,
+            },
+            FormatField {
+                name: "bitrate",
+                value: track.bitrate.map(|bitrate| bitrate.to_string()).unwrap_or_default(),
+                present: track.bitrate.is_some(),
+            },
+        ];
+
+        let parsed = expand_format(&self.format_string, &fields);
+
+        // The fixed part in front is left as the user wrote it
+        let fixed_len = self.format_string[..self.format_string.find('{').unwrap_or(0)]
+            .rfind('/')
+            .map_or(0, |split_pos| split_pos + 1);
+
+        let parsed = format!(
+            "{}{}",
+            &parsed[..fixed_len],
+            parsed[fixed_len..]
+                .split('/')
+                .map(|component| self.sanitizer.sanitize_component(component))
+                .collect::<Vec<_>>()
+                .join("/")
+        );
+
+        OutputFile {
+            root: self.root(),
+            dir: parsed.rfind('/').map(|split_pos| parsed[..=split_pos].to_owned()),
+            file: parsed,
+        }
+    }
+}
+
+// Expands {field} specifiers, and {field?...} segments whose contents are only kept when the field is present.
+// Fields can be followed by modifiers, as in {track_num:02} or {album:slug}. Unknown specifiers are left as they are
+// A field is used when its name is followed by the end of the placeholder, a condition or a modifier, so {album}
+// doesn't count {album_artist} in
+fn format_uses_field(format: &str, name: &str) -> bool {
+    ['}', '?', ':']
+        .iter()
+        .any(|end| format.contains(&format!("{{{}{}", name, end)))
+}
+
+// {album_artist} folders hold many albums, only the album name itself makes a folder per album
+fn format_has_album_dirs(format: &str) -> bool {
+    format.rfind('/').is_some_and(|split_pos| {
+        let dirs = &format[..split_pos];
+        format_uses_field(dirs, "album") || format_uses_field(dirs, "album_sort")
+    })
+}
+
+fn expand_format(format: &str, fields: &[FormatField]) -> String {
+    let mut expanded = String::new();
+    let mut rest = format;
+
+    while let Some(start) = rest.find('{') {
+        expanded.push_str(&rest[..start]);
+
+        let mut depth = 0;
+        let end = rest[start..].char_indices().find_map(|(pos, c)| {
+            match c {
+                '{' => depth += 1,
+                '}' => depth -= 1,
+                _ => {}
+            };
+            (depth == 0).then_some(start + pos)
+        });
+
+        let Some(end) = end else {
+            expanded.push_str(&rest[start..]);
+            return expanded;
+        };
+
+        let spec = &rest[start + 1..end];
+        let (name, segment) = match spec.split_once('?') {
+            Some((name, segment)) => (name, Some(segment)),
+            None => (spec, None),
+        };
+
+        let mut modifiers = name.split(':');
+        let name = modifiers.next().unwrap_or_default();
+
+        let value = |field: &FormatField| {
+            modifiers
+                .clone()
+                .try_fold(field.value.clone(), |value, modifier| apply_modifier(&value, modifier))
+        };
+
+        match (fields.iter().find(|field| field.name == name), segment) {
+            (Some(field), Some(segment)) if field.present => expanded.push_str(&expand_format(segment, fields)),
+            (Some(_), Some(_)) => {}
+            (Some(field), None) => match value(field) {
+                Some(value) => expanded.push_str(&value),
+                None => expanded.push_str(&rest[start..=end]),
+            },
+            (None, _) => expanded.push_str(&rest[start..=end]),
+        }
+
+        rest = &rest[end + 1..];
+    }
+
+    expanded.push_str(rest);
+    expanded
+}
+
+// A number pads the value with zeros up to that width, the rest change how it's written. None for unknown modifiers
+pub fn apply_modifier(value: &str, modifier: &str) -> Option<String> {
+    match modifier {
+        "lower" => Some(value.to_lowercase()),
+        "upper" => Some(value.to_uppercase()),
+        "slug" => Some(fold_name(value).replace(' ', "-")),
+        width => width
+            .parse::<usize>()
+            .ok()
+            .map(|width| format!("{:0>width$}", value, width = width)),
+    }
+}
+
+pub enum TrackWriteErrorKind {
+    FolderCreate,
+    FileCreate,
+    FileWrite,
+}
+
+impl ProcessErrorKind for TrackWriteErrorKind {}
+pub type TrackWriteError = ProcessError<TrackWriteErrorKind>;
+
+// Part files are kept together in a folder of the root that media servers are told to skip
+const PART_DIR: &str = ".rippify-parts";
+
+// Tracks are written under another name first, so a crash never leaves a partial file where a finished one would be.
+// Retries get a name of their own, a download that was given up on may still be writing to the last one
+pub fn track_part_file(output_file: &OutputFile, attempt: u32) -> String {
+    let name = &manifest::sha256(output_file.file.as_bytes())[..16];
+    let part_file = match attempt {
+        0 => format!("{}.part", name),
+        _ => format!("{}.{}.part", name, attempt),
+    };
+
+    path::Path::new(&output_file.root)
+        .join(PART_DIR)
+        .join(part_file)
+        .to_string_lossy()
+        .into_owned()
+}
+
+pub fn track_create_part(output_file: &OutputFile, attempt: u32) -> Result<fs::File, TrackWriteError> {
+    let part_dir = path::Path::new(&output_file.root).join(PART_DIR);
+
+    fs::create_dir_all(&part_dir)
+        .and_then(|_| manifest::mark_ignored(&part_dir))
+        .map_err(|e| TrackWriteError {
+            kind: TrackWriteErrorKind::FolderCreate,
+            error: e.into(),
+        })?;
+
+    fs::File::create(track_part_file(output_file, attempt)).map_err(|e| ProcessError {
+        kind: TrackWriteErrorKind::FileCreate,
+        error: e.into(),
+    })
+}
+
+// Moves a finished part file, which may have been written for another output file, to where it belongs
+pub fn track_finish_part(
+    part_file: &str,
+    output_file: OutputFile,
+    mtime: Option<time::SystemTime>,
+) -> Result<String, TrackWriteError> {
+    if let Some(path) = &output_file.dir {
+        fs::create_dir_all(path).map_err(|e| TrackWriteError {
+            kind: TrackWriteErrorKind::FolderCreate,
+            error: e.into(),
+        })?;
+    }
+
+    if let Some(mtime) = mtime {
+        fs::OpenOptions::new()
+            .write(true)
+            .open(part_file)
+            .and_then(|file| file.set_modified(mtime))
+            .map_err(|e| ProcessError {
+                kind: TrackWriteErrorKind::FileWrite,
+                error: e.into(),
+            })?;
+    }
+
+    fs::rename(part_file, &output_file.file).map_err(|e| ProcessError {
+        kind: TrackWriteErrorKind::FileWrite,
+        error: e.into(),
+    })?;
+
+    Ok(output_file.file)
+}
+
+// The tags and ogg pages written are already the same on every run, the file's modification time is the one thing
+// left that isn't. SOURCE_DATE_EPOCH is honored when set, otherwise the album's release date is used
+pub fn reproducible_mtime(track: &lsm::Track) -> time::SystemTime {
+    let secs = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<u64>().ok())
+        .unwrap_or(track.album.date.unix_timestamp().max(0) as u64);
+
+    time::UNIX_EPOCH + time::Duration::from_secs(secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn album_dirs_need_the_album_in_a_folder() {
+        assert!(format_has_album_dirs("{author}/{album}/{name}.{ext}"));
+        assert!(format_has_album_dirs("{author}/{album:slug}/{name}.{ext}"));
+        assert!(format_has_album_dirs("{author_sort}/{album_sort}/{name}.{ext}"));
+        assert!(format_has_album_dirs("{author}/{album} {year?({year})}/{name}.{ext}"));
+        assert!(!format_has_album_dirs("{album_artist}/{name}.{ext}"));
+        assert!(!format_has_album_dirs("{author}/{album} - {name}.{ext}"));
+        assert!(!format_has_album_dirs("{album}.{ext}"));
+    }
+
+    #[test]
+    fn formats_expand_fields_segments_and_modifiers() {
+        let field = |name, value: &str| FormatField {
+            name,
+            value: value.to_owned(),
+            present: !value.is_empty(),
+        };
+        let fields = [
+            field("author", "Daft Punk"),
+            field("album", "Discovery"),
+            field("name", "One More Time"),
+            field("disc_number", ""),
+            field("track_num", "1"),
+            field("year", "2001"),
+        ];
+
+        assert_eq!(
+            expand_format("{author}/{album}/{track_num:02} {name}", &fields),
+            "Daft Punk/Discovery/01 One More Time"
+        );
+        assert_eq!(
+            expand_format(
+                "{album}{year? ({year})}/{disc_number?Disc {disc_number}/}{name}",
+                &fields
+            ),
+            "Discovery (2001)/One More Time"
+        );
+        assert_eq!(expand_format("{author:lower:slug}", &fields), "daft-punk");
+        assert_eq!(expand_format("{album:upper}", &fields), "DISCOVERY");
+        assert_eq!(
+            expand_format("{unknown}/{name:bogus}", &fields),
+            "{unknown}/{name:bogus}"
+        );
+        assert_eq!(expand_format("{author", &fields), "{author");
+    }
+
+    #[test]
+    fn articles_move_to_the_end() {
+        let articles = vec!["The".to_owned(), "L'".to_owned()];
+
+        assert_eq!(move_article("The Beatles", &articles), "Beatles, The");
+        assert_eq!(move_article("the xx", &articles), "xx, the");
+        assert_eq!(move_article("L'Impératrice", &articles), "Impératrice, L'");
+        assert_eq!(move_article("Theatre of Tragedy", &articles), "Theatre of Tragedy");
+        assert_eq!(move_article("The", &articles), "The");
+    }
+
+    #[test]
+    fn featuring_credits_are_kept_stripped_or_normalized() {
+        assert_eq!(Featuring::Keep.apply("Song (ft. Someone)"), "Song (ft. Someone)");
+        assert_eq!(Featuring::Strip.apply("Song (feat. Someone)"), "Song");
+        assert_eq!(Featuring::Strip.apply("Song - Remix"), "Song - Remix");
+        assert_eq!(Featuring::Normalize.apply("Song ft. Someone"), "Song (feat. Someone)");
+        assert_eq!(
+            Featuring::Normalize.apply("Song [with Someone] - Remix"),
+            "Song (feat. Someone) - Remix"
+        );
+    }
+}
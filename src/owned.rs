@@ -0,0 +1,253 @@
+use crate::manifest::walk_dirs;
+use crate::matching::normalize;
+use librespot_metadata as lsm;
+use std::collections as coll;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::path;
+
+// Tracks found in music collections kept outside of rippify, so they aren't downloaded again at a lower quality.
+// They're recognized by the ISRC in their tags, or else by their artist and title
+#[derive(Default)]
+pub struct OwnedIndex {
+    isrcs: coll::HashMap<String, path::PathBuf>,
+    titles: coll::HashMap<(String, String), path::PathBuf>,
+}
+
+#[derive(Default)]
+struct FileTags {
+    isrc: Option<String>,
+    artist: Option<String>,
+    title: Option<String>,
+}
+
+impl OwnedIndex {
+    pub fn scan(&mut self, root: &path::Path) -> io::Result<usize> {
+        let mut count = 0;
+
+        for dir in walk_dirs(root)? {
+            for entry in fs::read_dir(&dir)?.filter_map(|entry| entry.ok()) {
+                let file = entry.path();
+
+                // Files that can't be read are skipped, they'll just never match
+                let tags = match read_tags(&file) {
+                    Ok(Some(tags)) => tags,
+                    _ => continue,
+                };
+
+                if let Some(isrc) = tags.isrc {
+                    self.isrcs.insert(isrc.replace('-', "").to_uppercase(), file.clone());
+                }
+
+                if let (Some(artist), Some(title)) = (tags.artist, tags.title) {
+                    self.titles.insert((normalize(&artist), normalize(&title)), file);
+                }
+
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    pub fn find(&self, track: &lsm::Track) -> Option<&path::PathBuf> {
+        let by_isrc = track
+            .external_ids
+            .iter()
+            .filter(|external_id| external_id.external_type == "isrc")
+            .find_map(|external_id| self.isrcs.get(&external_id.id.to_uppercase()));
+
+        by_isrc.or_else(|| {
+            let title = normalize(&track.name);
+
+            track
+                .artists
+                .iter()
+                .find_map(|artist| self.titles.get(&(normalize(&artist.name), title.clone())))
+        })
+    }
+}
+
+fn read_tags(file: &path::Path) -> io::Result<Option<FileTags>> {
+    let extension = file
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+
+    let comments = match extension.as_str() {
+        "flac" => read_flac_comments(file)?,
+        "ogg" | "oga" | "opus" => read_ogg_comments(file)?,
+        "mp3" => read_id3_frames(file)?,
+        _ => return Ok(None),
+    };
+
+    let mut tags = FileTags::default();
+
+    for (key, value) in comments {
+        match key.to_lowercase().as_str() {
+            "isrc" | "tsrc" => tags.isrc = tags.isrc.or(Some(value)),
+            "artist" | "tpe1" => tags.artist = tags.artist.or(Some(value)),
+            "title" | "tit2" => tags.title = tags.title.or(Some(value)),
+            _ => {}
+        }
+    }
+
+    Ok(Some(tags))
+}
+
+// https://xiph.org/flac/format.html#metadata_block, only the VORBIS_COMMENT block (type 4) is read
+fn read_flac_comments(file: &path::Path) -> io::Result<Vec<(String, String)>> {
+    let mut reader = io::BufReader::new(fs::File::open(file)?);
+    let mut magic = [0u8; 4];
+    reader.read_exact(&mut magic)?;
+
+    if &magic != b"fLaC" {
+        return Ok(Vec::new());
+    }
+
+    loop {
+        let mut header = [0u8; 4];
+        reader.read_exact(&mut header)?;
+
+        let len = u32::from_be_bytes([0, header[1], header[2], header[3]]);
+
+        if header[0] & 0x7f == 4 {
+            let mut block = vec![0u8; len as usize];
+            reader.read_exact(&mut block)?;
+
+            return Ok(parse_vorbis_comments(&block).unwrap_or_default());
+        }
+
+        if header[0] & 0x80 != 0 {
+            return Ok(Vec::new());
+        }
+
+        reader.seek_relative(len as i64)?;
+    }
+}
+
+// The comment header is the second packet of both vorbis and opus streams
+fn read_ogg_comments(file: &path::Path) -> io::Result<Vec<(String, String)>> {
+    let mut reader = ogg::PacketReader::new(io::BufReader::new(fs::File::open(file)?));
+
+    for _ in 0..2 {
+        let packet = match reader
+            .read_packet()
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+        {
+            Some(packet) => packet,
+            None => break,
+        };
+
+        if let Some(comments) = packet.data.strip_prefix(b"\x03vorbis") {
+            return Ok(parse_vorbis_comments(comments).unwrap_or_default());
+        }
+
+        if let Some(comments) = packet.data.strip_prefix(b"OpusTags") {
+            return Ok(parse_vorbis_comments(comments).unwrap_or_default());
+        }
+    }
+
+    Ok(Vec::new())
+}
+
+// https://www.xiph.org/vorbis/doc/v-comment.html, without the framing bit
+fn parse_vorbis_comments(data: &[u8]) -> Option<Vec<(String, String)>> {
+    let read_u32 = |pos: usize| -> Option<usize> {
+        Some(u32::from_le_bytes(data.get(pos..pos + 4)?.try_into().ok()?) as usize)
+    };
+
+    let mut pos = 4 + read_u32(0)?;
+    let count = read_u32(pos)?;
+    pos += 4;
+
+    let mut comments = Vec::<(String, String)>::new();
+
+    for _ in 0..count {
+        let len = read_u32(pos)?;
+        let comment = String::from_utf8_lossy(data.get(pos + 4..pos + 4 + len)?);
+        pos += 4 + len;
+
+        if let Some((key, value)) = comment.split_once('=') {
+            comments.push((key.to_owned(), value.to_owned()));
+        }
+    }
+
+    Some(comments)
+}
+
+// Only the text frames of ID3v2.3 and v2.4 tags at the start of the file
+fn read_id3_frames(file: &path::Path) -> io::Result<Vec<(String, String)>> {
+    let mut reader = io::BufReader::new(fs::File::open(file)?);
+    let mut header = [0u8; 10];
+    reader.read_exact(&mut header)?;
+
+    if &header[..3] != b"ID3" || !(3..=4).contains(&header[3]) {
+        return Ok(Vec::new());
+    }
+
+    let syncsafe = |bytes: &[u8]| bytes.iter().fold(0usize, |size, byte| (size << 7) | (*byte & 0x7f) as usize);
+
+    let mut tag = vec![0u8; syncsafe(&header[6..10])];
+    reader.read_exact(&mut tag)?;
+
+    let mut frames = Vec::<(String, String)>::new();
+    let mut pos = 0;
+
+    while let Some(frame) = tag.get(pos..pos + 10) {
+        if frame[0] == 0 {
+            break;
+        }
+
+        let id = String::from_utf8_lossy(&frame[..4]).into_owned();
+        let size = if header[3] == 4 {
+            syncsafe(&frame[4..8])
+        } else {
+            u32::from_be_bytes([frame[4], frame[5], frame[6], frame[7]]) as usize
+        };
+
+        let body = match tag.get(pos + 10..pos + 10 + size) {
+            Some(body) => body,
+            None => break,
+        };
+
+        if id.starts_with('T') {
+            if let Some(text) = decode_id3_text(body) {
+                frames.push((id, text));
+            }
+        }
+
+        pos += 10 + size;
+    }
+
+    Ok(frames)
+}
+
+fn decode_id3_text(body: &[u8]) -> Option<String> {
+    let (encoding, text) = body.split_first()?;
+
+    let text = match encoding {
+        0 => text.iter().map(|byte| *byte as char).collect(),
+        1 | 2 => {
+            let big_endian = *encoding == 2 || text.starts_with(&[0xfe, 0xff]);
+            let units = text
+                .chunks_exact(2)
+                .map(|pair| {
+                    if big_endian {
+                        u16::from_be_bytes([pair[0], pair[1]])
+                    } else {
+                        u16::from_le_bytes([pair[0], pair[1]])
+                    }
+                })
+                .filter(|unit| *unit != 0xfeff)
+                .collect::<Vec<_>>();
+
+            String::from_utf16_lossy(&units)
+        }
+        _ => String::from_utf8_lossy(text).into_owned(),
+    };
+
+    // v2.4 separates multiple values with nulls, only the first one is kept
+    text.split('\0').next().map(|text| text.to_owned())
+}
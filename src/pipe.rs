@@ -0,0 +1,69 @@
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::sync;
+use std::sync::atomic;
+use std::sync::mpsc;
+use std::thread;
+
+// Finished tracks are handed to a writer thread, since opening a fifo blocks until someone reads from it and
+// downloading shouldn't wait for the listener
+pub struct TrackPipe {
+    sender: mpsc::Sender<Vec<u8>>,
+    pending: sync::Arc<atomic::AtomicUsize>,
+    writer: thread::JoinHandle<()>,
+}
+
+impl TrackPipe {
+    pub fn open(path: &str) -> TrackPipe {
+        let (sender, receiver) = mpsc::channel::<Vec<u8>>();
+        let pending = sync::Arc::new(atomic::AtomicUsize::new(0));
+        let writer_pending = pending.clone();
+        let path = path.to_owned();
+
+        let writer = thread::spawn(move || {
+            let mut pipe: Option<fs::File> = None;
+
+            for buffer in receiver {
+                // A listener that went away (a closed player gives a broken pipe) is waited for again on the next track
+                if pipe.is_none() {
+                    pipe = fs::OpenOptions::new().append(true).create(true).open(&path).ok();
+                }
+
+                if let Some(file) = pipe.as_mut() {
+                    if let Err(err) = file.write_all(&buffer).and_then(|_| file.flush()) {
+                        if err.kind() == io::ErrorKind::BrokenPipe {
+                            pipe = None;
+                        }
+                    }
+                }
+
+                writer_pending.fetch_sub(1, atomic::Ordering::Relaxed);
+            }
+        });
+
+        TrackPipe {
+            sender,
+            pending,
+            writer,
+        }
+    }
+
+    pub fn send(&self, buffer: Vec<u8>) {
+        self.pending.fetch_add(1, atomic::Ordering::Relaxed);
+
+        if self.sender.send(buffer).is_err() {
+            self.pending.fetch_sub(1, atomic::Ordering::Relaxed);
+        }
+    }
+
+    pub fn pending(&self) -> usize {
+        self.pending.load(atomic::Ordering::Relaxed)
+    }
+
+    // Waits for the tracks still queued to be read
+    pub fn finish(self) {
+        drop(self.sender);
+        let _ = self.writer.join();
+    }
+}
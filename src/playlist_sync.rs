@@ -0,0 +1,79 @@
+use rippify::manifest::mark_ignored;
+use serde::{Deserialize, Serialize};
+use std::collections as coll;
+use std::fs;
+use std::io;
+use std::path;
+use std::time;
+
+pub struct SyncParams {
+    pub dir: String,
+    pub prune: bool,
+    pub archive_dir: Option<String>,
+    pub trash_retention: Option<time::Duration>,
+}
+
+// What a synced directory holds: the file written for every track of the playlist, relative to the directory
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+pub struct SyncManifest {
+    pub playlist: String,
+    pub tracks: coll::BTreeMap<String, String>,
+}
+
+impl SyncManifest {
+    pub fn path(dir: &path::Path) -> path::PathBuf {
+        dir.join(".rippify-sync.json")
+    }
+
+    pub fn load(dir: &path::Path) -> io::Result<SyncManifest> {
+        match fs::read(SyncManifest::path(dir)) {
+            Ok(buffer) => Ok(serde_json::from_slice(&buffer)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(SyncManifest::default()),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn save(&self, dir: &path::Path) -> io::Result<()> {
+        let mut manifest = serde_json::to_vec_pretty(self)?;
+        manifest.push(b'\n');
+
+        let tmp_path = dir.join(".rippify-sync.json.tmp");
+        fs::write(&tmp_path, manifest)?;
+        fs::rename(tmp_path, SyncManifest::path(dir))
+    }
+}
+
+// Takes a file of a track that left the playlist out of the directory, either for good or into `archive_dir`.
+// Folders left empty behind it are removed too
+pub fn remove_file(dir: &path::Path, file: &str, archive_dir: Option<&path::Path>) -> io::Result<()> {
+    let path = dir.join(file);
+
+    match archive_dir {
+        Some(archive_dir) => {
+            let archived = archive_dir.join(file);
+
+            // Inside the synced folder, removed tracks are kept out of media servers and of the library
+            fs::create_dir_all(archive_dir)?;
+
+            if fs::canonicalize(archive_dir)?.starts_with(fs::canonicalize(dir)?) {
+                mark_ignored(archive_dir)?;
+            }
+
+            if let Some(parent) = archived.parent() {
+                fs::create_dir_all(parent)?;
+            }
+
+            fs::rename(&path, archived)?;
+        }
+        None => fs::remove_file(&path)?,
+    }
+
+    for parent in path.ancestors().skip(1) {
+        if parent == dir || fs::remove_dir(parent).is_err() {
+            break;
+        }
+    }
+
+    Ok(())
+}
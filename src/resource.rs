@@ -0,0 +1,521 @@
+use crate::manifest;
+use crate::metadata;
+use async_recursion::async_recursion;
+use librespot_core as lsc;
+use librespot_metadata as lsm;
+use lsm::Metadata;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use std::fmt;
+
+#[derive(Clone)]
+pub enum ResourceKind {
+    Track,
+    Playlist,
+    Album,
+    Artist,
+}
+
+impl fmt::Display for ResourceKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ResourceKind::Track => write!(f, "track"),
+            ResourceKind::Playlist => write!(f, "playlist"),
+            ResourceKind::Album => write!(f, "album"),
+            ResourceKind::Artist => write!(f, "artist"),
+        }
+    }
+}
+
+impl ResourceKind {
+    fn to_url_regex(&self) -> regex::Regex {
+        regex::Regex::new(&format!(
+            r"^(http(s)?://)?open\.spotify\.com/{}/([[:alnum:]]{{22}})$",
+            self
+        ))
+        .unwrap()
+    }
+
+    fn to_uri_regex(&self) -> regex::Regex {
+        regex::Regex::new(&format!(r"^spotify:{}:([[:alnum:]]{{22}})$", self)).unwrap()
+    }
+}
+
+// Which of an artist's releases an artist input stands for. Singles come with the albums by default, what the
+// artist appears on and compilations only when asked for, they're mostly tracks of other artists and duplicates
+#[derive(Clone, Copy, PartialEq)]
+pub enum AlbumGroup {
+    Albums,
+    Singles,
+    AppearsOn,
+    Compilations,
+}
+
+pub struct ArtistScope {
+    pub album_groups: Vec<AlbumGroup>,
+    // Only the artist's most played tracks instead of any releases
+    pub top_tracks: bool,
+}
+
+impl Default for ArtistScope {
+    fn default() -> Self {
+        ArtistScope {
+            album_groups: vec![AlbumGroup::Albums, AlbumGroup::Singles],
+            top_tracks: false,
+        }
+    }
+}
+
+impl ArtistScope {
+    // A comma separated list of albums, singles, appears-on and compilations, or all of them
+    pub fn from_list(list: &str) -> Option<ArtistScope> {
+        let mut album_groups = Vec::<AlbumGroup>::new();
+
+        for name in list.split(',').map(|name| name.trim()) {
+            let named: &[AlbumGroup] = match name {
+                "albums" => &[AlbumGroup::Albums],
+                "singles" => &[AlbumGroup::Singles],
+                "appears-on" => &[AlbumGroup::AppearsOn],
+                "compilations" => &[AlbumGroup::Compilations],
+                "all" => &[
+                    AlbumGroup::Albums,
+                    AlbumGroup::Singles,
+                    AlbumGroup::AppearsOn,
+                    AlbumGroup::Compilations,
+                ],
+                _ => return None,
+            };
+
+            for album_group in named {
+                if !album_groups.contains(album_group) {
+                    album_groups.push(*album_group);
+                }
+            }
+        }
+
+        Some(ArtistScope {
+            album_groups,
+            top_tracks: false,
+        })
+    }
+}
+
+pub struct InputResource {
+    pub kind: ResourceKind,
+    pub id: lsc::SpotifyId,
+}
+
+impl InputResource {
+    #[async_recursion]
+    pub async fn get_track_groups(
+        &self,
+        session: &lsc::Session,
+        metadata_cache: &metadata::MetadataCache,
+        artist_scope: &ArtistScope,
+    ) -> Result<Vec<TrackGroup>, librespot_core::error::Error> {
+        let mut groups: Vec<TrackGroup> = Vec::new();
+
+        match self.kind {
+            ResourceKind::Track => {
+                groups.push(TrackGroup {
+                    title: None,
+                    name: None,
+                    tracks: vec![self.id],
+                    radio: None,
+                });
+            }
+            ResourceKind::Playlist => {
+                let playlist = metadata_cache.playlist(session, &self.id).await?;
+                groups.push(TrackGroup {
+                    title: Some(format!("playlist: {} ({})", playlist.name(), self.id.to_base62()?)),
+                    name: Some(playlist.name().to_owned()),
+                    tracks: playlist.tracks().copied().collect(),
+                    radio: None,
+                });
+            }
+            ResourceKind::Album => {
+                // Album tracks are listed in disc and track order
+                let album = metadata_cache.album(session, &self.id).await?;
+                groups.push(TrackGroup {
+                    title: Some(format!("album: {} ({})", album.name, self.id.to_base62()?)),
+                    name: Some(album.name.clone()),
+                    tracks: album.tracks().copied().collect(),
+                    radio: None,
+                });
+            }
+            ResourceKind::Artist if artist_scope.top_tracks => {
+                let artist = metadata_cache.artist(session, &self.id).await?;
+                groups.push(TrackGroup {
+                    title: Some(format!("top tracks: {} ({})", artist.name, self.id.to_base62()?)),
+                    name: Some(format!("{} - Top Tracks", artist.name)),
+                    tracks: artist
+                        .top_tracks
+                        .for_country(&session.country())
+                        .iter()
+                        .copied()
+                        .collect(),
+                    radio: None,
+                });
+            }
+            ResourceKind::Artist => {
+                let artist = metadata_cache.artist(session, &self.id).await?;
+
+                for album_group in &artist_scope.album_groups {
+                    let albums = match album_group {
+                        AlbumGroup::Albums => &artist.albums,
+                        AlbumGroup::Singles => &artist.singles,
+                        AlbumGroup::AppearsOn => &artist.appears_on_albums,
+                        AlbumGroup::Compilations => &artist.compilations,
+                    };
+
+                    for album in albums.iter().flat_map(|album_group| album_group.0.iter()) {
+                        groups.extend(
+                            InputResource {
+                                kind: ResourceKind::Album,
+                                id: *album,
+                            }
+                            .get_track_groups(session, metadata_cache, artist_scope)
+                            .await?,
+                        );
+                    }
+                }
+            }
+        }
+
+        Ok(groups)
+    }
+
+    // Spotify's "radio" stations give recommendations seeded from a single track or artist
+    pub async fn get_radio_tracks(
+        &self,
+        session: &lsc::Session,
+        count: usize,
+    ) -> Result<Vec<lsc::SpotifyId>, librespot_core::error::Error> {
+        #[derive(serde::Deserialize)]
+        struct StationTrack {
+            uri: String,
+        }
+
+        #[derive(serde::Deserialize)]
+        struct Station {
+            tracks: Vec<StationTrack>,
+        }
+
+        let seed = format!("spotify:{}:{}", self.kind, self.id.to_base62()?);
+        let response = session
+            .spclient()
+            .get_apollo_station("stations", &seed, Some(count), Vec::new(), false)
+            .await?;
+
+        let station: Station =
+            serde_json::from_slice(&response).map_err(librespot_core::error::Error::failed_precondition)?;
+
+        Ok(station
+            .tracks
+            .iter()
+            .filter_map(|track| lsc::SpotifyId::from_uri(&track.uri).ok())
+            .take(count)
+            .collect())
+    }
+}
+
+// Tracks are processed in input order; tracks that came from an album or playlist are printed under its title
+pub struct TrackGroup {
+    pub title: Option<String>,
+    // What the group is called on its own, for the playlists and albums that have a name
+    pub name: Option<String>,
+    pub tracks: Vec<lsc::SpotifyId>,
+    pub radio: Option<manifest::RadioSeed>,
+}
+
+// The logged in account's own library, which isn't a playlist and can only be read through the web api
+pub enum LibraryCollection {
+    LikedSongs,
+    SavedAlbums,
+}
+
+impl fmt::Display for LibraryCollection {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LibraryCollection::LikedSongs => write!(f, "liked songs"),
+            LibraryCollection::SavedAlbums => write!(f, "saved albums"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct WebApiPage<T> {
+    items: Vec<T>,
+    next: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct WebApiItem {
+    uri: String,
+}
+
+// Liked local files have no track
+#[derive(Deserialize)]
+struct SavedTrack {
+    track: Option<WebApiItem>,
+}
+
+#[derive(Deserialize)]
+struct SavedAlbum {
+    album: WebApiItem,
+}
+
+#[derive(Deserialize)]
+struct PlaylistOwner {
+    id: String,
+}
+
+#[derive(Deserialize)]
+struct UserPlaylist {
+    uri: String,
+    owner: PlaylistOwner,
+}
+
+impl LibraryCollection {
+    // Only liked songs have a uri of their own, the user in it doesn't matter since it's always our own library
+    pub fn from_line(line: &str) -> Option<LibraryCollection> {
+        let collection =
+            regex::Regex::new(r"^(spotify:user:[^:]+:collection|(https?://)?open\.spotify\.com/collection/tracks)$")
+                .unwrap();
+
+        collection
+            .is_match(line.trim())
+            .then_some(LibraryCollection::LikedSongs)
+    }
+
+    pub async fn get_track_groups(
+        &self,
+        session: &lsc::Session,
+        metadata_cache: &metadata::MetadataCache,
+    ) -> Result<Vec<TrackGroup>, librespot_core::error::Error> {
+        match self {
+            LibraryCollection::LikedSongs => {
+                let saved: Vec<SavedTrack> = get_web_api_pages(session, "user-library-read", "me/tracks").await?;
+
+                Ok(vec![TrackGroup {
+                    title: Some("liked songs".to_owned()),
+                    name: Some("Liked Songs".to_owned()),
+                    tracks: saved
+                        .iter()
+                        .filter_map(|saved| saved.track.as_ref())
+                        .filter_map(|track| lsc::SpotifyId::from_uri(&track.uri).ok())
+                        .collect(),
+                    radio: None,
+                }])
+            }
+            LibraryCollection::SavedAlbums => {
+                let saved: Vec<SavedAlbum> = get_web_api_pages(session, "user-library-read", "me/albums").await?;
+                let mut groups = Vec::<TrackGroup>::new();
+
+                for album in saved {
+                    if let Ok(id) = lsc::SpotifyId::from_uri(&album.album.uri) {
+                        groups.extend(
+                            InputResource {
+                                kind: ResourceKind::Album,
+                                id,
+                            }
+                            .get_track_groups(session, metadata_cache, &ArtistScope::default())
+                            .await?,
+                        );
+                    }
+                }
+
+                Ok(groups)
+            }
+        }
+    }
+}
+
+// Another user's profile, standing for every public playlist they made. Only the ones they own are taken, the
+// profile also lists the public playlists they follow
+pub struct UserProfile {
+    pub username: String,
+}
+
+impl fmt::Display for UserProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "user {}", self.username)
+    }
+}
+
+impl UserProfile {
+    pub fn from_line(line: &str) -> Option<UserProfile> {
+        let profile =
+            regex::Regex::new(r"^((https?://)?open\.spotify\.com/user/([^/:?]+)|spotify:user:([^:]+))$").unwrap();
+        let captures = profile.captures(line.trim())?;
+
+        Some(UserProfile {
+            username: captures.get(3).or(captures.get(4))?.as_str().to_owned(),
+        })
+    }
+
+    pub async fn get_track_groups(
+        &self,
+        session: &lsc::Session,
+        metadata_cache: &metadata::MetadataCache,
+    ) -> Result<Vec<TrackGroup>, librespot_core::error::Error> {
+        let endpoint = format!("users/{}/playlists", self.username);
+        let playlists: Vec<UserPlaylist> = get_web_api_pages(session, "playlist-read-private", &endpoint).await?;
+        let mut groups = Vec::<TrackGroup>::new();
+
+        for playlist in playlists.iter().filter(|playlist| playlist.owner.id == self.username) {
+            if let Ok(id) = lsc::SpotifyId::from_uri(&playlist.uri) {
+                groups.extend(
+                    InputResource {
+                        kind: ResourceKind::Playlist,
+                        id,
+                    }
+                    .get_track_groups(session, metadata_cache, &ArtistScope::default())
+                    .await?,
+                );
+            }
+        }
+
+        Ok(groups)
+    }
+}
+
+// Follows the pages of a web api endpoint, 50 items at a time
+async fn get_web_api_pages<T: DeserializeOwned>(
+    session: &lsc::Session,
+    scope: &str,
+    endpoint: &str,
+) -> Result<Vec<T>, librespot_core::error::Error> {
+    let token = session.token_provider().get_token(scope).await?;
+    let client = reqwest::Client::new();
+
+    let mut items = Vec::<T>::new();
+    let mut next = Some(format!("https://api.spotify.com/v1/{}?limit=50", endpoint));
+
+    while let Some(url) = next {
+        let page = client
+            .get(url)
+            .bearer_auth(&token.access_token)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(lsc::Error::unavailable)?
+            .json::<WebApiPage<T>>()
+            .await
+            .map_err(lsc::Error::failed_precondition)?;
+
+        items.extend(page.items);
+        next = page.next;
+    }
+
+    Ok(items)
+}
+
+// Shortcuts for the account's personalized playlists, which have a different id for every user
+pub enum PlaylistKeyword {
+    DiscoverWeekly,
+    ReleaseRadar,
+}
+
+impl fmt::Display for PlaylistKeyword {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PlaylistKeyword::DiscoverWeekly => write!(f, "discover-weekly"),
+            PlaylistKeyword::ReleaseRadar => write!(f, "release-radar"),
+        }
+    }
+}
+
+impl PlaylistKeyword {
+    pub fn from_line(line: &str) -> Option<PlaylistKeyword> {
+        match line.trim().to_lowercase().as_str() {
+            "discover-weekly" => Some(PlaylistKeyword::DiscoverWeekly),
+            "release-radar" => Some(PlaylistKeyword::ReleaseRadar),
+            _ => None,
+        }
+    }
+
+    fn playlist_name(&self) -> &'static str {
+        match self {
+            PlaylistKeyword::DiscoverWeekly => "Discover Weekly",
+            PlaylistKeyword::ReleaseRadar => "Release Radar",
+        }
+    }
+
+    // The playlist names are localized, but the generated ids share a fixed prefix for each kind
+    fn id_prefix(&self) -> &'static str {
+        match self {
+            PlaylistKeyword::DiscoverWeekly => "37i9dQZEVXc",
+            PlaylistKeyword::ReleaseRadar => "37i9dQZEVXb",
+        }
+    }
+
+    pub async fn find_playlist(&self, session: &lsc::Session) -> Result<InputResource, librespot_core::error::Error> {
+        let root = lsm::playlist::RootPlaylist::get_root(session).await?;
+
+        for item in root.0.contents.items.iter() {
+            if item.id.item_type != lsc::spotify_id::SpotifyItemType::Playlist {
+                continue;
+            }
+
+            let playlist = lsm::Playlist::get(session, &item.id).await?;
+
+            if playlist.owner_username == "spotify"
+                && (playlist.name().eq_ignore_ascii_case(self.playlist_name())
+                    || item.id.to_base62()?.starts_with(self.id_prefix()))
+            {
+                return Ok(InputResource {
+                    kind: ResourceKind::Playlist,
+                    id: item.id,
+                });
+            }
+        }
+
+        Err(librespot_core::error::Error::not_found(format!(
+            "\"{}\" is not in your library, follow it from the spotify app first",
+            self.playlist_name()
+        )))
+    }
+}
+
+pub fn get_resource_from_line(line: &str) -> Result<InputResource, &str> {
+    if let Some(id) = is_resource(line, ResourceKind::Track) {
+        Ok(InputResource {
+            kind: ResourceKind::Track,
+            id,
+        })
+    //
+    } else if let Some(id) = is_resource(line, ResourceKind::Album) {
+        Ok(InputResource {
+            kind: ResourceKind::Album,
+            id,
+        })
+    //
+    } else if let Some(id) = is_resource(line, ResourceKind::Playlist) {
+        Ok(InputResource {
+            kind: ResourceKind::Playlist,
+            id,
+        })
+    //
+    } else if let Some(id) = is_resource(line, ResourceKind::Artist) {
+        Ok(InputResource {
+            kind: ResourceKind::Artist,
+            id,
+        })
+    //
+    } else {
+        Err(line)
+    }
+}
+
+fn is_resource(line: &str, res: ResourceKind) -> Option<lsc::SpotifyId> {
+    if let Some(captures) = res.to_url_regex().captures(line).or(res.to_uri_regex().captures(line)) {
+        let id_str = captures.iter().last().unwrap().unwrap().as_str();
+        let id = lsc::SpotifyId::from_base62(id_str).unwrap();
+
+        Some(id)
+    //
+    } else {
+        None
+    }
+}
@@ -0,0 +1,156 @@
+// Which characters are kept in path components. posix only rules out what can't be in a file name at all, windows
+// also what Windows and NTFS mounts refuse, and strict keeps to plain ascii that is safe everywhere
+#[derive(Clone, Copy, PartialEq)]
+pub enum SanitizePolicy {
+    Posix,
+    Windows,
+    Strict,
+}
+
+impl SanitizePolicy {
+    pub fn from_name(policy: &str) -> Option<SanitizePolicy> {
+        match policy {
+            "posix" => Some(SanitizePolicy::Posix),
+            "windows" => Some(SanitizePolicy::Windows),
+            "strict" => Some(SanitizePolicy::Strict),
+            _ => None,
+        }
+    }
+
+    pub fn allows(&self, c: char) -> bool {
+        match self {
+            SanitizePolicy::Posix => c != '/' && c != '\0',
+            SanitizePolicy::Windows => !c.is_control() && !"<>:\"/\\|?*".contains(c),
+            SanitizePolicy::Strict => c.is_ascii_alphanumeric() || " -_.,()&'+!".contains(c),
+        }
+    }
+
+    // Spaces were always what slashes in track names turned into, underscores stand out less where a lot gets
+    // replaced
+    pub fn default_replacement(&self) -> char {
+        match self {
+            SanitizePolicy::Posix => ' ',
+            SanitizePolicy::Windows | SanitizePolicy::Strict => '_',
+        }
+    }
+}
+
+pub struct Sanitizer {
+    pub policy: SanitizePolicy,
+    pub replacement: char,
+}
+
+const WINDOWS_RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+impl Sanitizer {
+    pub fn new(policy: SanitizePolicy, replacement: Option<char>) -> Result<Sanitizer, String> {
+        let replacement = replacement.unwrap_or(policy.default_replacement());
+
+        if !policy.allows(replacement) {
+            return Err(format!("\"{}\" is not allowed in file names either", replacement));
+        }
+
+        Ok(Sanitizer { policy, replacement })
+    }
+
+    // For the value of a single field. Strict transliterates accented letters instead of replacing them
+    pub fn sanitize(&self, value: &str) -> String {
+        value
+            .chars()
+            .map(|c| match self.policy {
+                SanitizePolicy::Strict => fold_accent(c),
+                _ => c,
+            })
+            .map(|c| if self.policy.allows(c) { c } else { self.replacement })
+            .collect()
+    }
+
+    // For a whole path component, once the fields in it are expanded. Windows drops trailing dots and spaces on its
+    // own and can't open files named after devices, whatever their extension
+    pub fn sanitize_component(&self, component: &str) -> String {
+        if self.policy == SanitizePolicy::Posix || component == "." || component == ".." {
+            return component.to_owned();
+        }
+
+        let component = component.trim_end_matches(['.', ' ']);
+        let stem = component.split('.').next().unwrap_or_default();
+
+        if WINDOWS_RESERVED
+            .iter()
+            .any(|reserved| stem.eq_ignore_ascii_case(reserved))
+        {
+            format!("{}{}{}", stem, self.replacement, &component[stem.len()..])
+        } else {
+            component.to_owned()
+        }
+    }
+}
+
+pub fn fold_accent(c: char) -> char {
+    let folded = match c.to_lowercase().next().unwrap_or(c) {
+        'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' => 'a',
+        'ç' | 'ć' | 'č' => 'c',
+        'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ę' | 'ě' => 'e',
+        'ì' | 'í' | 'î' | 'ï' | 'ī' => 'i',
+        'ñ' | 'ń' | 'ň' => 'n',
+        'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' | 'ő' => 'o',
+        'ù' | 'ú' | 'û' | 'ü' | 'ū' | 'ů' | 'ű' => 'u',
+        'ý' | 'ÿ' => 'y',
+        'ś' | 'š' => 's',
+        'ź' | 'ż' | 'ž' => 'z',
+        'ł' => 'l',
+        'ř' => 'r',
+        _ => return c,
+    };
+
+    if c.is_uppercase() {
+        folded.to_ascii_uppercase()
+    } else {
+        folded
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sanitizer(policy: SanitizePolicy) -> Sanitizer {
+        Sanitizer::new(policy, None).unwrap()
+    }
+
+    #[test]
+    fn fields_lose_what_the_policy_does_not_allow() {
+        assert_eq!(
+            sanitizer(SanitizePolicy::Posix).sanitize("AC/DC: Live?"),
+            "AC DC: Live?"
+        );
+        assert_eq!(
+            sanitizer(SanitizePolicy::Windows).sanitize("AC/DC: Live?"),
+            "AC_DC_ Live_"
+        );
+        assert_eq!(
+            sanitizer(SanitizePolicy::Strict).sanitize("Beyoncé – Déjà Vu"),
+            "Beyonce _ Deja Vu"
+        );
+    }
+
+    #[test]
+    fn components_avoid_what_windows_refuses() {
+        let windows = sanitizer(SanitizePolicy::Windows);
+
+        assert_eq!(windows.sanitize_component("Con.ogg"), "Con_.ogg");
+        assert_eq!(windows.sanitize_component("Console.ogg"), "Console.ogg");
+        assert_eq!(windows.sanitize_component("Vol. 2. "), "Vol. 2");
+        assert_eq!(windows.sanitize_component(".."), "..");
+        assert_eq!(sanitizer(SanitizePolicy::Posix).sanitize_component("Con. "), "Con. ");
+    }
+
+    #[test]
+    fn replacements_must_be_allowed_themselves() {
+        assert!(Sanitizer::new(SanitizePolicy::Windows, Some(':')).is_err());
+        assert!(Sanitizer::new(SanitizePolicy::Strict, Some('-')).is_ok());
+    }
+}
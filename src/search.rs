@@ -0,0 +1,75 @@
+use librespot_core as lsc;
+use serde::Deserialize;
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct SearchResults {
+    pub tracks: SearchHits<TrackHit>,
+    pub albums: SearchHits<AlbumHit>,
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+pub struct SearchHits<T> {
+    pub hits: Vec<T>,
+    pub total: usize,
+}
+
+impl<T> Default for SearchHits<T> {
+    fn default() -> Self {
+        SearchHits {
+            hits: Vec::new(),
+            total: 0,
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct ArtistHit {
+    pub name: String,
+    pub uri: String,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct AlbumHit {
+    pub name: String,
+    pub uri: String,
+    pub artists: Vec<ArtistHit>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+pub struct TrackHit {
+    pub name: String,
+    pub uri: String,
+    pub artists: Vec<ArtistHit>,
+    pub album: AlbumHit,
+    pub duration: u32,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct SearchResponse {
+    results: SearchResults,
+}
+
+pub async fn search(session: &lsc::Session, query: &str, limit: usize) -> Result<SearchResults, lsc::Error> {
+    let uri = format!(
+        "hm://searchview/km/v4/search/{}?entityVersion=2&limit={}&imageSize=large&catalogue=&country={}&locale=en&platform=zelda&username={}",
+        urlencoding::encode(query),
+        limit,
+        session.country(),
+        urlencoding::encode(&session.username())
+    );
+
+    let response = session.mercury().get(uri)?.await?;
+    let payload = response
+        .payload
+        .first()
+        .ok_or(lsc::Error::unavailable("empty search response"))?;
+
+    let parsed: SearchResponse = serde_json::from_slice(payload).map_err(lsc::Error::failed_precondition)?;
+    Ok(parsed.results)
+}
@@ -0,0 +1,262 @@
+use crate::classical::{track_composers, Movement};
+use crate::download::AudioContainer;
+use crate::error::{ProcessError, ProcessErrorKind};
+use crate::lyrics::TrackLyrics;
+use crate::manifest::AudioInfo;
+use crate::metadata::AlbumInfo;
+use crate::output::Featuring;
+use lewton::header as lhr;
+use librespot_metadata as lsm;
+use std::io;
+
+pub fn track_add_metadata_tags(
+    track_buffer: Vec<u8>,
+    comment_header: &lhr::CommentHeader,
+    container: AudioContainer,
+    audio_info_tags: bool,
+) -> Result<(Vec<u8>, Option<AudioInfo>), TagsWriteError> {
+    // Only vorbis comments are supported for now, anything else is passed through untouched
+    if container != AudioContainer::Ogg {
+        return Ok((track_buffer, None));
+    }
+
+    let mut out_buffer = Vec::<u8>::new();
+    let audio_info = replace_header_comment(
+        io::Cursor::new(&track_buffer),
+        &mut out_buffer,
+        comment_header,
+        audio_info_tags,
+    )?;
+
+    Ok((out_buffer, audio_info))
+}
+
+pub fn track_comment_header(
+    track: &lsm::Track,
+    album_info: &AlbumInfo,
+    featuring: &Featuring,
+    lyrics: Option<&TrackLyrics>,
+    requested_id: Option<&str>,
+) -> lhr::CommentHeader {
+    let mut metadata = lhr::CommentHeader {
+        vendor: String::from("Ogg"),
+        comment_list: Vec::new(),
+    };
+
+    metadata
+        .comment_list
+        .push((String::from("title"), featuring.apply(&track.name)));
+    metadata
+        .comment_list
+        .push((String::from("album"), track.album.name.clone()));
+
+    metadata.comment_list.extend(
+        track
+            .artists
+            .iter()
+            .map(|artist| (String::from("artist"), artist.name.clone()))
+            .collect::<Vec<_>>(),
+    );
+
+    metadata.comment_list.extend(
+        track
+            .album
+            .artists
+            .first()
+            .map(|artist| (String::from("albumartist"), artist.name.clone())),
+    );
+
+    metadata.comment_list.extend(
+        track_composers(track)
+            .iter()
+            .map(|composer| (String::from("composer"), composer.name.clone())),
+    );
+
+    if let Some(movement) = Movement::from_name(&track.name) {
+        metadata.comment_list.push((String::from("work"), movement.work));
+        metadata
+            .comment_list
+            .push((String::from("movementname"), movement.name));
+        metadata
+            .comment_list
+            .push((String::from("movement"), movement.number.to_string()));
+    }
+
+    metadata
+        .comment_list
+        .push((String::from("tracknumber"), track.number.to_string()));
+    metadata
+        .comment_list
+        .push((String::from("discnumber"), track.disc_number.to_string()));
+
+    // Zero means the album couldn't be fetched, better no total than a wrong one
+    let total_tracks = album_info.disc_track_count(track.disc_number);
+
+    if total_tracks > 0 {
+        metadata
+            .comment_list
+            .push((String::from("totaltracks"), total_tracks.to_string()));
+    }
+
+    if track.album.date.year() > 0 {
+        metadata.comment_list.push((
+            String::from("date"),
+            format!(
+                "{:04}-{:02}-{:02}",
+                track.album.date.year(),
+                track.album.date.month() as u8,
+                track.album.date.day()
+            ),
+        ));
+    }
+
+    // When an alternative was downloaded in place of the track asked for, both ids are kept to find it again by either
+    if let Some(requested_id) = requested_id {
+        metadata
+            .comment_list
+            .push((String::from("spotify_id"), track.id.to_base62().unwrap_or_default()));
+        metadata
+            .comment_list
+            .push((String::from("spotify_requested_id"), requested_id.to_owned()));
+    }
+
+    if let Some(lyrics) = lyrics {
+        metadata
+            .comment_list
+            .push((String::from("lyrics"), lyrics.text.clone()));
+    }
+
+    metadata
+}
+
+// Reverse implementation of https://github.com/RustAudio/lewton/blob/bb2955b717094b40260902cf2f8dd9c5ea62a84a/src/header.rs#L309
+fn make_header_comment(header: &lhr::CommentHeader) -> Option<Vec<u8>> {
+    let mut packet: Vec<u8> = vec![];
+
+    // 'V' 'O' 'R' 'B' 'I' 'S'
+    packet.extend([0x03, 0x76, 0x6F, 0x72, 0x62, 0x69, 0x73] as [u8; 7]);
+
+    let vendor_buf = header.vendor.as_bytes();
+    let vendor_len = TryInto::<u32>::try_into(vendor_buf.len()).ok()?.to_le_bytes();
+
+    packet.extend(vendor_len);
+    packet.extend(vendor_buf);
+
+    let comments_len = TryInto::<u32>::try_into(header.comment_list.len()).ok()?.to_le_bytes();
+
+    packet.extend(comments_len);
+
+    for comment in &header.comment_list {
+        let comment_buf = format!("{}={}", comment.0, comment.1);
+        let comment_buf = comment_buf.as_bytes();
+        let comment_len = TryInto::<u32>::try_into(comment_buf.len()).ok()?.to_le_bytes();
+
+        packet.extend(comment_len);
+        packet.extend(comment_buf);
+    }
+
+    packet.extend([0x01] as [u8; 1]);
+    Some(packet)
+}
+
+pub enum TagsWriteErrorKind {
+    Read,
+    Write,
+    Header,
+}
+
+impl ProcessErrorKind for TagsWriteErrorKind {}
+pub type TagsWriteError = ProcessError<TagsWriteErrorKind>;
+
+// The packet reader only seeks to find its way back after running into something that isn't an ogg page, which a
+// stream being downloaded can't do. That's left to fail as a read error
+struct ForwardOnly<R>(R);
+
+impl<R: io::Read> io::Read for ForwardOnly<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R> io::Seek for ForwardOnly<R> {
+    fn seek(&mut self, _: io::SeekFrom) -> io::Result<u64> {
+        Err(io::Error::new(io::ErrorKind::Unsupported, "ogg stream is corrupted"))
+    }
+}
+
+// Copies an ogg stream with its comment header replaced, a packet at a time, so it works the same on a buffer and on
+// audio that is still being downloaded
+// Based on https://github.com/RustAudio/ogg/blob/0910d8d57645eccc1a1400731fefef376859c661/examples/repack.rs#L52
+// The identification header comes first, so what it says about the audio can also go in the tags when
+// `audio_info_tags` is set
+pub fn replace_header_comment<R: io::Read, W: io::Write>(
+    ogg_reader: R,
+    ogg_writer: W,
+    comment_header: &lhr::CommentHeader,
+    audio_info_tags: bool,
+) -> Result<Option<AudioInfo>, TagsWriteError> {
+    let mut reader = ogg::PacketReader::new(ForwardOnly(ogg_reader));
+    let mut writer = ogg::PacketWriter::new(ogg_writer);
+
+    let mut overwrote_header = false;
+    let mut audio_info = None;
+
+    loop {
+        if let Some(mut packet) = reader.read_packet().map_err(|e| TagsWriteError {
+            kind: TagsWriteErrorKind::Read,
+            error: e.into(),
+        })? {
+            if audio_info.is_none() {
+                if let Ok(ident) = lhr::read_header_ident(&packet.data) {
+                    audio_info = Some(AudioInfo {
+                        sample_rate: ident.audio_sample_rate,
+                        channels: ident.audio_channels,
+                        nominal_bitrate: ident.bitrate_nominal,
+                    });
+                }
+            }
+
+            if !overwrote_header {
+                if let Ok(_) = lhr::read_header_comment(&packet.data) {
+                    let mut comment_header = comment_header.clone();
+
+                    if let (true, Some(audio_info)) = (audio_info_tags, &audio_info) {
+                        comment_header.comment_list.extend([
+                            (String::from("samplerate"), audio_info.sample_rate.to_string()),
+                            (String::from("channels"), audio_info.channels.to_string()),
+                            (String::from("bitrate"), audio_info.nominal_bitrate.to_string()),
+                        ]);
+                    }
+
+                    packet.data = make_header_comment(&comment_header).ok_or(TagsWriteError {
+                        kind: TagsWriteErrorKind::Header,
+                        error: "invalid header comment data".into(),
+                    })?;
+                    overwrote_header = true;
+                }
+            }
+
+            let packet_inf = if packet.last_in_stream() {
+                ogg::PacketWriteEndInfo::EndStream
+            } else if packet.last_in_page() {
+                ogg::PacketWriteEndInfo::EndPage
+            } else {
+                ogg::PacketWriteEndInfo::NormalPacket
+            };
+
+            let packet_serial = packet.stream_serial();
+            let packet_absgp = packet.absgp_page();
+
+            writer
+                .write_packet(packet.data, packet_serial, packet_inf, packet_absgp)
+                .map_err(|e| TagsWriteError {
+                    kind: TagsWriteErrorKind::Write,
+                    error: e.into(),
+                })?;
+        } else {
+            break;
+        }
+    }
+
+    Ok(audio_info)
+}
@@ -0,0 +1,116 @@
+use crate::error::{ProcessError, ProcessErrorKind};
+use std::fmt;
+use std::process;
+use tokio::io::AsyncWriteExt;
+
+#[derive(Clone, Copy, PartialEq)]
+pub enum Encoding {
+    Mp3,
+    Flac,
+    Opus,
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Encoding::Mp3 => write!(f, "mp3"),
+            Encoding::Flac => write!(f, "flac"),
+            Encoding::Opus => write!(f, "opus"),
+        }
+    }
+}
+
+impl Encoding {
+    pub const ALL: [Encoding; 3] = [Encoding::Mp3, Encoding::Flac, Encoding::Opus];
+
+    pub fn from_name(encoding: &str) -> Option<Encoding> {
+        match encoding {
+            "mp3" => Some(Encoding::Mp3),
+            "flac" => Some(Encoding::Flac),
+            "opus" => Some(Encoding::Opus),
+            _ => None,
+        }
+    }
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Encoding::Mp3 => "mp3",
+            Encoding::Flac => "flac",
+            Encoding::Opus => "opus",
+        }
+    }
+
+    fn ffmpeg_args(&self) -> &'static [&'static str] {
+        match self {
+            Encoding::Mp3 => &["-f", "mp3", "-c:a", "libmp3lame", "-q:a", "0", "-id3v2_version", "3"],
+            Encoding::Flac => &["-f", "flac", "-c:a", "flac"],
+            Encoding::Opus => &["-f", "opus", "-c:a", "libopus", "-b:a", "160k"],
+        }
+    }
+}
+
+// Transcoding only works with an ffmpeg on PATH, which is looked for when it's run
+pub fn ffmpeg_available() -> bool {
+    process::Command::new("ffmpeg")
+        .arg("-version")
+        .stdout(process::Stdio::null())
+        .stderr(process::Stdio::null())
+        .status()
+        .is_ok_and(|status| status.success())
+}
+
+pub enum TranscodeErrorKind {
+    Spawn,
+    Encode,
+}
+
+impl ProcessErrorKind for TranscodeErrorKind {}
+pub type TranscodeError = ProcessError<TranscodeErrorKind>;
+
+// Converts through an ffmpeg process, fed and read over pipes so nothing touches the disk. The tags already written
+// to the input are carried over to the output's own tag format
+pub async fn transcode(buffer: Vec<u8>, encoding: Encoding) -> Result<Vec<u8>, TranscodeError> {
+    let mut child = tokio::process::Command::new("ffmpeg")
+        .args(["-hide_banner", "-loglevel", "error", "-i", "pipe:0", "-map_metadata", "0", "-vn"])
+        .args(encoding.ffmpeg_args())
+        .arg("pipe:1")
+        .stdin(process::Stdio::piped())
+        .stdout(process::Stdio::piped())
+        .stderr(process::Stdio::piped())
+        .spawn()
+        .map_err(|e| ProcessError {
+            kind: TranscodeErrorKind::Spawn,
+            error: e.into(),
+        })?;
+
+    let mut stdin = child.stdin.take().unwrap();
+
+    // ffmpeg starts writing before it has read everything, so both ends have to be served at the same time
+    let (written, output) = tokio::join!(
+        async move {
+            let written = stdin.write_all(&buffer).await;
+            drop(stdin);
+            written
+        },
+        child.wait_with_output()
+    );
+
+    let output = output.map_err(|e| ProcessError {
+        kind: TranscodeErrorKind::Encode,
+        error: e.into(),
+    })?;
+
+    if !output.status.success() {
+        return Err(ProcessError {
+            kind: TranscodeErrorKind::Encode,
+            error: String::from_utf8_lossy(&output.stderr).trim().to_owned().into(),
+        });
+    }
+
+    written.map_err(|e| ProcessError {
+        kind: TranscodeErrorKind::Encode,
+        error: e.into(),
+    })?;
+
+    Ok(output.stdout)
+}
@@ -0,0 +1,54 @@
+use std::fs;
+use std::io;
+use std::path;
+use std::time;
+
+// Files taken out by a sync are moved under the user's data directory instead of being deleted, in one folder per
+// run named after its unix time. Runs older than the retention period are emptied for good
+pub fn dir() -> io::Result<path::PathBuf> {
+    dirs::data_dir()
+        .map(|dir| dir.join("rippify").join("trash"))
+        .ok_or(io::Error::new(
+            io::ErrorKind::NotFound,
+            "cannot find user data directory",
+        ))
+}
+
+pub fn run_dir() -> io::Result<path::PathBuf> {
+    Ok(dir()?.join(unix_time().to_string()))
+}
+
+// Removes the runs trashed longer ago than `retention`, or all of them without one. Returns how many were removed
+pub fn empty(retention: Option<time::Duration>) -> io::Result<usize> {
+    let dir = dir()?;
+
+    if !dir.exists() {
+        return Ok(0);
+    }
+
+    let now = unix_time();
+    let mut count = 0;
+
+    for entry in fs::read_dir(dir)?.filter_map(|entry| entry.ok()) {
+        let trashed = match entry.file_name().to_string_lossy().parse::<u64>() {
+            Ok(trashed) => trashed,
+            Err(_) => continue,
+        };
+
+        if retention.is_some_and(|retention| now.saturating_sub(trashed) < retention.as_secs()) {
+            continue;
+        }
+
+        fs::remove_dir_all(entry.path())?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+fn unix_time() -> u64 {
+    time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}